@@ -5,6 +5,7 @@ import { IconDownload } from './IconDownload';
 
 export type IProps = {
   seed: string;
+  connectionId: number;
   file: FileInfo;
 };
 
@@ -28,9 +29,9 @@ const humanFileSize = (bytes: number, dp = 1) => {
 };
 
 export const FileButton = (props: IProps) => {
-  const { seed, file } = props;
+  const { seed, connectionId, file } = props;
 
-  const downloadLink = `${process.env.REACT_APP_API_URL}/sessions/files/${file.id}?session_seed=${seed}`;
+  const downloadLink = `${process.env.REACT_APP_API_URL}/sessions/files/${file.id}?session_seed=${seed}&connection_id=${connectionId}`;
 
   return (
     <a className="file-button" href={downloadLink}>
@@ -43,7 +43,7 @@ export const MainPage = () => {
     <div className="content main-page">
       <div className="files-list">
         {session.files.map(file => (
-          <FileButton seed={session.seed} file={file} />
+          <FileButton seed={session.seed} connectionId={session.connection_id} file={file} />
         ))}
         <FileInput onDrop={onFilesAdded} />
       </div>
@@ -1,7 +1,7 @@
 import React from 'react';
 
 import { v4 as uuidv4 } from 'uuid';
-import { SessionSocketBuilder, SessionSocket, FileInfo } from './sessionSocket';
+import { SessionSocketBuilder, SessionSocket, FileInfo, PROTOCOL_VERSION } from './sessionSocket';
 
 export const StateContext = React.createContext<PossibleStates>({
   kind: 'uninitialized'
@@ -59,6 +59,8 @@ export class State extends React.Component<{}, PossibleStates> {
     };
 
     this.builder = new SessionSocketBuilder();
+    // Server speaks the legacy protocol until the version is selected
+    this.builder.onConnected = socket => socket.send('hello', { version: PROTOCOL_VERSION });
     this.builder.responseHandlers = {
       created: ({ phrase }) => {
         this.setState({
@@ -102,7 +104,7 @@ export class State extends React.Component<{}, PossibleStates> {
           files: files.filter(file => file.id !== id)
         });
       },
-      file_requested: ({ id }) => {
+      file_requested: ({ id, request_id, range }) => {
         if (this.state.kind !== 'connected') {
           return;
         }
@@ -112,11 +114,11 @@ export class State extends React.Component<{}, PossibleStates> {
           return;
         }
 
-        const url = `${process.env.REACT_APP_API_URL}/sessions/files/${id}`;
+        const url = `${process.env.REACT_APP_API_URL}/sessions/files/${id}/${request_id}`;
 
         fetch(url, {
           method: 'POST',
-          body: localFile,
+          body: range != null ? localFile.slice(range.start, range.end + 1) : localFile,
           headers: {
             'X-Session-Seed': this.state.seed,
             'Content-Type': 'application/octet-stream'
@@ -125,14 +127,16 @@ export class State extends React.Component<{}, PossibleStates> {
           .then(console.log)
           .catch(console.warn);
       },
-      peer_not_found: () => {
-        alert('Peer not found!');
-      },
-      session_not_found: () => {
-        alert('Session not found!');
+      transfer_failed: ({ id, reason }) => {
+        if (this.state.kind !== 'connected') {
+          return;
+        }
+
+        const file = this.state.files.find(file => file.id === id);
+        alert(`Transfer of ${file?.name ?? 'file'} failed: ${reason}`);
       },
-      file_count_limit_reached: () => {
-        alert('File count limit reached!');
+      error: ({ message }) => {
+        alert(`Error: ${message}`);
       }
     };
 
@@ -7,8 +7,8 @@ export class SessionSocket {
     this.socket = socket;
   }
 
-  send<K extends WsRequestType>(kind: K, content: WsRequestContent[K]) {
-    this.socket.send(JSON.stringify({ type: kind, content } as WsRequest));
+  send<K extends WsRequestType>(kind: K, content: WsRequestContent[K], requestId?: string) {
+    this.socket.send(JSON.stringify({ type: kind, content, request_id: requestId } as WsRequest));
   }
 
   close() {
@@ -22,6 +22,7 @@ export class SessionSocketBuilder {
   private url: string;
 
   public responseHandlers: { [T in WsResponseType]?: WsResponseHandler<T> } = {};
+  public onConnected: ((socket: SessionSocket) => void) | null = null;
   public onDisconnected: (() => void) | null = null;
 
   constructor(url?: string) {
@@ -35,6 +36,8 @@ export class SessionSocketBuilder {
 
   build(): SessionSocket {
     const socket = new WebSocket(this.url);
+    const sessionSocket = new SessionSocket(socket);
+    this.onConnected && (socket.onopen = () => this.onConnected?.(sessionSocket));
     socket.onmessage = message => {
       const data: WsResponse = JSON.parse(message.data);
       console.log(message);
@@ -43,32 +46,81 @@ export class SessionSocketBuilder {
     };
     this.onDisconnected && (socket.onclose = this.onDisconnected);
 
-    return new SessionSocket(socket);
+    return sessionSocket;
   }
 }
 
+export const PROTOCOL_VERSION = 2;
+
 // requests
 
 export type WsRequestContent = {
+  hello: { version: number; profile?: PeerProfile | null };
+  update_profile: PeerProfile;
   connect: { phrase: string };
-  add_file: { id: string, name: string; mime_type: string; size: number };
+  add_file: { id: string, name: string; mime_type: string; size: number; hash?: FileHash | null; persist?: boolean };
   remove_file: { id: string };
+  add_bundle: { id: string; name: string; entries: BundleEntry[] };
+  resume: { token: string };
+  create_invite: null;
+  start_upload: { id: string; request_id: string };
+  rtc_offer: { connection_id: number; transfer_id: string; id: string; sdp: string };
+  rtc_answer: { connection_id: number; transfer_id: string; sdp: string };
+  rtc_candidate: { connection_id: number; transfer_id: string; candidate: RTCIceCandidateInit };
+  rtc_close: { connection_id: number; transfer_id: string; reason?: string | null };
+  kick_peer: { connection_id: number };
+  lock_session: { locked: boolean };
+  transfer_host: { connection_id: number };
 };
 export type WsRequestType = keyof WsRequestContent;
-export type WsRequestContainer<T extends WsRequestType> = { type: T; content: WsRequestContent[T] } | never;
+export type WsRequestContainer<T extends WsRequestType> =
+  | { type: T; content: WsRequestContent[T]; request_id?: string }
+  | never;
 export type WsRequest = WsRequestContainer<WsRequestType>;
 
 // responses
 
 export type WsResponseContent = {
-  created: { phrase: string };
-  connected: { connection_id: number; seed: string; files: FileInfo[] };
+  hello: { versions: number[]; capabilities: Capabilities };
+  created: { phrase: string; versions: number[]; capabilities: Capabilities };
+  connected: {
+    connection_id: number;
+    seed: string;
+    files: FileInfo[];
+    bundles: BundleInfo[];
+    resume_token: string;
+    join_phrase: string;
+    host: number;
+    locked: boolean;
+  };
+  peer_list: { peers: PeerInfo[] };
+  peer_joined: PeerInfo;
+  peer_updated: PeerInfo;
+  peer_left: { connection_id: number };
+  invite_created: { phrase: string; expires_at: string };
+  host_changed: { connection_id: number };
+  session_locked: { locked: boolean };
+  join_phrase_changed: { join_phrase: string };
+  kicked: null;
   file_added: FileInfo;
   file_removed: { id: string };
-  file_requested: { id: string };
-  peer_not_found: null;
-  session_not_found: null;
-  file_count_limit_reached: null;
+  file_stored: { id: string; expires_at: string };
+  bundle_added: BundleInfo;
+  bundle_removed: { id: string };
+  file_unavailable: { id: string };
+  file_available: { id: string };
+  file_requested: { id: string; request_id: string; range: ByteRange | null };
+  transfer_completed: { id: string; request_id: string; bytes: number };
+  transfer_failed: { id: string; request_id: string; reason: string };
+  hash_mismatch: { id: string; request_id: string; expected: FileHash; actual: FileHash };
+  upload_credits: { request_id: string; credits: number };
+  rtc_offer: { connection_id: number; transfer_id: string; id: string; sdp: string };
+  rtc_answer: { connection_id: number; transfer_id: string; sdp: string };
+  rtc_candidate: { connection_id: number; transfer_id: string; candidate: RTCIceCandidateInit };
+  rtc_close: { connection_id: number; transfer_id: string; reason: string | null };
+  session_expired: null;
+  ack: { request_id: string | null };
+  error: { request_id: string | null; code: ErrorCode; message: string };
 };
 export type WsResponseType = keyof WsResponseContent;
 export type WsResponseContainer<T extends WsResponseType> = { type: T; content: WsResponseContent[T] } | never;
@@ -81,7 +133,76 @@ export type FileInfo = {
   name: string;
   mime_type: string;
   size: number;
+  hash: FileHash | null;
+  expires_at: string | null;
+  available: boolean;
+  connection_id: number;
+};
+
+export type ErrorCode =
+  | 'invalid_request'
+  | 'unknown_type'
+  | 'peer_not_found'
+  | 'session_not_found'
+  | 'file_not_found'
+  | 'file_count_limit_reached'
+  | 'file_already_exists'
+  | 'invalid_file_hash'
+  | 'invalid_bundle'
+  | 'storage_unavailable'
+  | 'resume_failed'
+  | 'unsupported_version'
+  | 'upload_not_found'
+  | 'credits_exceeded'
+  | 'rtc_unavailable'
+  | 'not_allowed'
+  | 'session_locked';
+
+export type Capabilities = {
+  max_file_count: number;
+  resumable_transfers: boolean;
+  session_resume: boolean;
+  store_and_forward: boolean;
+  binary_frames: boolean;
+  webrtc: boolean;
+  ice_servers: RTCIceServer[];
+};
+
+export type DeviceType = "desktop" | "mobile" | "tablet" | "bot" | "unknown";
+
+export type PeerProfile = {
+  name: string;
+  device_type: DeviceType;
+  user_agent?: string | null;
+};
+
+export type PeerInfo = PeerProfile & {
   connection_id: number;
+  online: boolean;
+};
+
+export type FileHash = {
+  algorithm: 'sha256' | 'blake3';
+  value: string;
+};
+
+export type BundleInfo = {
+  id: string;
+  name: string;
+  entries: BundleEntry[];
+  available: boolean;
+  connection_id: number;
+};
+
+export type BundleEntry = {
+  id: string;
+  path: string;
+  size: number;
+};
+
+export type ByteRange = {
+  start: number;
+  end: number;
 };
 
 // handlers
@@ -1,10 +1,11 @@
 use super::Context;
+use crate::services::sessions::{queue_metrics, ArchiveRequest, FileRequest, NegotiatedCodec, RangeSpec, RequestError, TransferError};
 
 use http::HeaderValue;
 use serde::Deserialize;
 use uuid::Uuid;
 use warp::filters::BoxedFilter;
-use warp::Filter;
+use warp::{Filter, Reply};
 
 pub fn api_v1(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
     warp::path("api")
@@ -12,7 +13,11 @@ pub fn api_v1(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
         .and(
             get_sessions_files(ctx.clone())
                 .or(post_sessions_files(ctx.clone()))
-                .or(ws_sessions_socket(ctx)),
+                .or(post_sessions_files_legacy(ctx.clone()))
+                .or(get_sessions_bundles(ctx.clone()))
+                .or(get_sessions_archive(ctx.clone()))
+                .or(ws_sessions_socket(ctx))
+                .or(get_metrics_queues()),
         )
         .boxed()
 }
@@ -21,55 +26,183 @@ fn get_sessions_files(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
     #[derive(Debug, Deserialize)]
     struct Params {
         session_seed: String,
+        /// Connection to notify about the transfer result
+        connection_id: Option<usize>,
     }
 
     warp::path!("sessions" / "files" / Uuid)
         .and(warp::get())
         .and(warp::query::<Params>())
+        .and(warp::header::optional::<String>("Range"))
+        .and(warp::header::optional::<String>("If-Range"))
         .and(with_ctx(ctx))
-        .and_then(|id: Uuid, params: Params, ctx: Context| async move {
-            use futures::StreamExt;
-
-            log::debug!("Received file get: {}, {:?}", id, params);
-
-            match ctx.session_service.request_file(id, params.session_seed).await {
-                Some((file_info, rx)) => {
-                    let body: hyper::Body = hyper::Body::wrap_stream(rx.map(Ok::<_, std::convert::Infallible>));
-                    hyper::Response::builder()
-                        .header(
-                            http::header::CONTENT_DISPOSITION,
-                            format!("attachment; filename=\"{}\"", file_info.name.replace('"', "\"")),
-                        )
-                        .body(body)
-                        .map_err(|e| {
-                            println!("error: {:?}", e);
+        .and_then(
+            |id: Uuid, params: Params, range: Option<String>, if_range: Option<String>, ctx: Context| async move {
+                log::debug!("Received file get: {}, {:?}, range: {:?}", id, params, range);
+
+                let etag = format!("\"{}\"", id);
+
+                // Ignore malformed ranges and ranges for the stale representation
+                let range = match if_range {
+                    Some(if_range) if if_range.trim() != etag => None,
+                    _ => range.and_then(|range| range.parse::<RangeSpec>().ok()),
+                };
+
+                match ctx
+                    .session_service
+                    .request_file(id, params.session_seed, range, params.connection_id)
+                    .await
+                {
+                    Ok(FileRequest { file, range, data, .. }) => {
+                        // Failed transfer aborts the response instead of completing it with a truncated file
+                        let body: hyper::Body = hyper::Body::wrap_stream(data);
+                        let mut response = hyper::Response::builder()
+                            .header(http::header::CONTENT_DISPOSITION, content_disposition(&file.name))
+                            .header(http::header::CONTENT_LENGTH, range.map(|range| range.len()).unwrap_or(file.size))
+                            .header(http::header::ACCEPT_RANGES, "bytes")
+                            .header(http::header::ETAG, etag);
+
+                        // Whole file responses are aborted if the relayed bytes don't match the digest
+                        if let Some(digest) = file.hash.as_ref().and_then(|hash| hash.repr_digest()) {
+                            response = response.header("Repr-Digest", digest);
+                        }
+
+                        if let Some(range) = range {
+                            response = response
+                                .status(http::StatusCode::PARTIAL_CONTENT)
+                                .header(http::header::CONTENT_RANGE, range.content_range(file.size));
+                        }
+
+                        response.body(body).map_err(|e| {
+                            log::warn!("failed to build file response: {}", e);
                             warp::reject()
                         })
+                    }
+                    Err(RequestError::RangeNotSatisfiable { size }) => hyper::Response::builder()
+                        .status(http::StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(http::header::CONTENT_RANGE, format!("bytes */{}", size))
+                        .body(hyper::Body::empty())
+                        .map_err(|e| {
+                            log::warn!("failed to build file response: {}", e);
+                            warp::reject()
+                        }),
+                    Err(RequestError::FileUnavailable) => hyper::Response::builder()
+                        .status(http::StatusCode::SERVICE_UNAVAILABLE)
+                        .body(hyper::Body::empty())
+                        .map_err(|e| {
+                            log::warn!("failed to build file response: {}", e);
+                            warp::reject()
+                        }),
+                    Err(_) => Err(warp::reject()),
                 }
-                None => Err(warp::reject()),
-            }
-        })
+            },
+        )
         .boxed()
 }
 
 fn post_sessions_files(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
-    warp::path!("sessions" / "files" / Uuid)
+    warp::path!("sessions" / "files" / Uuid / Uuid)
         .and(warp::post())
         .and(warp::header::value("X-Session-Seed"))
-        .and(warp::header::value("Content-Length"))
+        .and(warp::header::<usize>("Content-Length"))
         .and(warp::filters::body::stream())
         .and(with_ctx(ctx))
-        .and_then(|id: Uuid, seed: HeaderValue, size: HeaderValue, data, ctx: Context| async move {
-            println!("Downloading file: {:?} bytes", size);
+        .and_then(
+            |id: Uuid, request_id: Uuid, seed: HeaderValue, size: usize, data, ctx: Context| async move {
+                log::debug!("Received file upload: {}, request: {}, {} bytes", id, request_id, size);
+
+                let seed = match seed.to_str().ok() {
+                    Some(seed) => seed.to_owned(),
+                    None => return Err(warp::reject()),
+                };
+
+                let result = ctx.session_service.upload_file(id, request_id, seed, size, data).await;
+                upload_response(id, result)
+            },
+        )
+        .boxed()
+}
 
+/// Upload of the protocol v1 owner, which is notified only with the file id
+fn post_sessions_files_legacy(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
+    warp::path!("sessions" / "files" / Uuid)
+        .and(warp::post())
+        .and(warp::header::value("X-Session-Seed"))
+        .and(warp::header::<usize>("Content-Length"))
+        .and(warp::filters::body::stream())
+        .and(with_ctx(ctx))
+        .and_then(|id: Uuid, seed: HeaderValue, size: usize, data, ctx: Context| async move {
             let seed = match seed.to_str().ok() {
                 Some(seed) => seed.to_owned(),
                 None => return Err(warp::reject()),
             };
 
-            match ctx.session_service.upload_file(id, seed, data).await {
-                Some(_) => Ok(warp::reply()),
-                None => Err(warp::reject()),
+            let result = ctx.session_service.upload_legacy_file(id, seed, size, data).await;
+            upload_response(id, result)
+        })
+        .boxed()
+}
+
+fn upload_response(id: Uuid, result: Result<usize, TransferError>) -> Result<impl warp::Reply, warp::Rejection> {
+    match result {
+        Ok(_) => Ok(warp::reply::with_status(warp::reply(), http::StatusCode::OK)),
+        Err(TransferError::RequestNotFound) => Err(warp::reject()),
+        Err(e) => {
+            log::warn!("upload of file {} failed: {}", id, e);
+            let status = match e {
+                TransferError::TooLarge(_) => http::StatusCode::PAYLOAD_TOO_LARGE,
+                TransferError::ReceiversGone => http::StatusCode::GONE,
+                _ => http::StatusCode::BAD_REQUEST,
+            };
+            Ok(warp::reply::with_status(warp::reply(), status))
+        }
+    }
+}
+
+fn get_sessions_bundles(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
+    #[derive(Debug, Deserialize)]
+    struct Params {
+        session_seed: String,
+        /// Connection to notify about the transfer results
+        connection_id: Option<usize>,
+    }
+
+    warp::path!("sessions" / "bundles" / Uuid)
+        .and(warp::get())
+        .and(warp::query::<Params>())
+        .and(with_ctx(ctx))
+        .and_then(|id: Uuid, params: Params, ctx: Context| async move {
+            log::debug!("Received bundle get: {}, {:?}", id, params);
+
+            match ctx
+                .session_service
+                .request_bundle(id, params.session_seed, params.connection_id)
+                .await
+            {
+                Ok(archive) => archive_response(archive),
+                Err(_) => Err(warp::reject()),
+            }
+        })
+        .boxed()
+}
+
+fn get_sessions_archive(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
+    #[derive(Debug, Deserialize)]
+    struct Params {
+        /// Connection to notify about the transfer results
+        connection_id: Option<usize>,
+    }
+
+    warp::path!("sessions" / String / "archive")
+        .and(warp::get())
+        .and(warp::query::<Params>())
+        .and(with_ctx(ctx))
+        .and_then(|seed: String, params: Params, ctx: Context| async move {
+            log::debug!("Received archive get: {:?}", params);
+
+            match ctx.session_service.request_session_archive(seed, params.connection_id).await {
+                Ok(archive) => archive_response(archive),
+                Err(_) => Err(warp::reject()),
             }
         })
         .boxed()
@@ -78,13 +211,108 @@ fn post_sessions_files(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
 fn ws_sessions_socket(ctx: Context) -> BoxedFilter<(impl warp::Reply,)> {
     warp::path!("sessions" / "socket")
         .and(warp::ws())
+        .and(warp::header::optional::<String>("Sec-WebSocket-Protocol"))
+        .and(warp::header::optional::<String>("User-Agent"))
         .and(with_ctx(ctx))
-        .map(|ws: warp::ws::Ws, ctx: Context| {
-            ws.on_upgrade(move |websocket| async move { ctx.session_service.handle_connection(websocket).await })
-        })
+        .map(
+            |ws: warp::ws::Ws, protocols: Option<String>, user_agent: Option<String>, ctx: Context| {
+                let (codec, protocol) = NegotiatedCodec::negotiate(protocols.as_deref());
+
+                let mut response = ws
+                    .on_upgrade(move |websocket| async move {
+                        ctx.session_service.clone().handle_connection(websocket, codec, user_agent).await
+                    })
+                    .into_response();
+
+                // Subprotocol is confirmed only if the client requested it
+                if let Some(protocol) = protocol {
+                    response
+                        .headers_mut()
+                        .insert(http::header::SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol));
+                }
+                response
+            },
+        )
+        .boxed()
+}
+
+/// Outgoing websocket queues of all connections, so slow consumers can be watched while the server runs
+fn get_metrics_queues() -> BoxedFilter<(impl warp::Reply,)> {
+    warp::path!("metrics" / "queues")
+        .and(warp::get())
+        .map(|| warp::reply::json(&queue_metrics()))
         .boxed()
 }
 
+fn archive_response(archive: ArchiveRequest) -> Result<hyper::Response<hyper::Body>, warp::Rejection> {
+    let mut response = hyper::Response::builder()
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            content_disposition(&format!("{}.zip", archive.name)),
+        )
+        .header(http::header::CONTENT_TYPE, "application/zip");
+
+    if let Some(size) = archive.size {
+        response = response.header(http::header::CONTENT_LENGTH, size);
+    }
+
+    response.body(hyper::Body::wrap_stream(archive.data)).map_err(|e| {
+        log::warn!("failed to build archive response: {}", e);
+        warp::reject()
+    })
+}
+
+/// Attachment header with an ASCII fallback and the exact UTF-8 name (RFC 6266)
+fn content_disposition(name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", fallback, encoded)
+}
+
 fn with_ctx(ctx: Context) -> impl Filter<Extract = (Context,), Error = std::convert::Infallible> + Clone {
     warp::any().map(move || ctx.clone())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_disposition_escapes_names() {
+        assert_eq!(
+            content_disposition("report.pdf"),
+            "attachment; filename=\"report.pdf\"; filename*=UTF-8''report.pdf"
+        );
+        assert_eq!(
+            content_disposition("a\"b\\c.txt"),
+            "attachment; filename=\"a_b_c.txt\"; filename*=UTF-8''a%22b%5Cc.txt"
+        );
+        assert_eq!(
+            content_disposition("отчёт 1.txt"),
+            "attachment; filename=\"_____ 1.txt\"; filename*=UTF-8''%D0%BE%D1%82%D1%87%D1%91%D1%82%201.txt"
+        );
+    }
+
+    #[test]
+    fn content_disposition_is_a_valid_header() {
+        for name in &["a\r\nSet-Cookie: x", "tab\there", "\u{7f}"] {
+            let value = content_disposition(name);
+            assert!(HeaderValue::from_str(&value).is_ok(), "{:?}", value);
+        }
+    }
+}
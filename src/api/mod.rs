@@ -10,7 +10,7 @@ pub async fn serve(ctx: Context) {
     let api = filters::api_v1(ctx);
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["Content-Type", "Content-Length", "X-Session-Seed"])
+        .allow_headers(vec!["Content-Type", "Content-Length", "X-Session-Seed", "Range", "If-Range"])
         .allow_methods(vec!["GET", "OPTIONS", "POST", "DELETE", "PUT"]);
     let log = warp::log("fbox");
 
@@ -6,6 +6,7 @@ mod settings;
 use crate::api::Context;
 use crate::prelude::*;
 use crate::services::sessions::SessionService;
+use crate::services::telegram::TelegramService;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -15,7 +16,14 @@ async fn main() -> Result<()> {
 
 async fn run() -> Result<()> {
     let settings = Arc::new(Settings::new()?);
-    let session_service = SessionService::new(&settings);
+    let session_service = SessionService::new(&settings)?;
+
+    tokio::spawn(session_service.clone().run_session_reaper());
+    tokio::spawn(session_service.clone().run_storage_cleanup());
+
+    if let Some(telegram) = &settings.telegram {
+        tokio::spawn(TelegramService::new(telegram, session_service.clone()).run());
+    }
 
     let ctx = Context { settings, session_service };
 
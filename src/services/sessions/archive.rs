@@ -0,0 +1,245 @@
+use futures::StreamExt;
+use uuid::Uuid;
+
+use super::relay::{self, RelayRx, RelayTx, TransferError};
+use super::websocket::ConnectionId;
+use super::zip::ZipWriter;
+use super::{decode_seed, RequestError, SessionService};
+use crate::prelude::*;
+
+pub struct ArchiveRequest {
+    /// File name of the archive without extension
+    pub name: String,
+    /// Exact archive size, unknown if unavailable entries can be skipped
+    pub size: Option<u64>,
+    pub data: RelayRx,
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    pub id: Uuid,
+    pub path: String,
+    pub size: usize,
+}
+
+impl SessionService {
+    /// Requests all bundle entries one by one and streams them as a single ZIP archive
+    pub async fn request_bundle(
+        self: Arc<Self>,
+        id: Uuid,
+        seed: String,
+        requester: Option<ConnectionId>,
+    ) -> Result<ArchiveRequest, RequestError> {
+        let decoded_seed = decode_seed(&seed).map_err(|_| RequestError::SessionNotFound)?;
+        let session = self
+            .sessions
+            .read()
+            .await
+            .get(&decoded_seed)
+            .ok_or(RequestError::SessionNotFound)?
+            .clone();
+
+        let bundle = session.read().await.bundles.get(&id).ok_or(RequestError::FileNotFound)?.clone();
+
+        let entries = bundle
+            .entries
+            .into_iter()
+            .map(|entry| ArchiveEntry {
+                id: entry.id,
+                path: entry.path,
+                size: entry.size,
+            })
+            .collect::<Vec<_>>();
+
+        let size = ZipWriter::archive_size(entries.iter().map(|entry| (entry.path.as_str(), entry.size as u64)));
+        let data = self.stream_archive(seed, requester, entries, Some(size));
+
+        Ok(ArchiveRequest {
+            name: bundle.name,
+            size: Some(size),
+            data,
+        })
+    }
+
+    /// Requests all session files one by one and streams them as a single ZIP archive,
+    /// files which can't be downloaded are listed in the manifest
+    pub async fn request_session_archive(
+        self: Arc<Self>,
+        seed: String,
+        requester: Option<ConnectionId>,
+    ) -> Result<ArchiveRequest, RequestError> {
+        let decoded_seed = decode_seed(&seed).map_err(|_| RequestError::SessionNotFound)?;
+        let session = self
+            .sessions
+            .read()
+            .await
+            .get(&decoded_seed)
+            .ok_or(RequestError::SessionNotFound)?
+            .clone();
+
+        let mut files = session.read().await.files.values().cloned().collect::<Vec<_>>();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut paths = std::collections::HashSet::new();
+        paths.insert(MANIFEST_PATH.to_owned());
+
+        let entries = files
+            .into_iter()
+            .map(|file| ArchiveEntry {
+                id: file.id,
+                path: unique_path(&mut paths, &file.name),
+                size: file.size,
+            })
+            .collect::<Vec<_>>();
+
+        let data = self.stream_archive(seed, requester, entries, None);
+
+        Ok(ArchiveRequest {
+            name: ARCHIVE_NAME.to_owned(),
+            size: None,
+            data,
+        })
+    }
+
+    /// Unavailable entries abort the archive if its size is known, otherwise they are skipped
+    fn stream_archive(
+        self: Arc<Self>,
+        seed: String,
+        requester: Option<ConnectionId>,
+        entries: Vec<ArchiveEntry>,
+        size: Option<u64>,
+    ) -> RelayRx {
+        let (mut tx, rx) = relay::relay_channel(self.relay_capacity(), size.unwrap_or_default() as usize);
+        let skip_unavailable = size.is_none();
+
+        tokio::spawn(async move {
+            let mut zip = ZipWriter::new();
+            let mut skipped = Vec::new();
+
+            for entry in entries {
+                let result = match self.open_archive_entry(&seed, requester, &entry).await {
+                    Ok((data, first)) => self.write_archive_entry(&mut zip, &mut tx, &entry, data, first).await,
+                    Err(e) if skip_unavailable => {
+                        log::debug!("archive entry {} skipped: {}", entry.id, e);
+                        skipped.push(format!("{}: {}\n", entry.path, e));
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                };
+
+                if let Err(e) = result {
+                    log::debug!("archive entry {} failed: {}", entry.id, e);
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            }
+
+            if !skipped.is_empty() {
+                let manifest = bytes::Bytes::from(skipped.concat());
+                let mut crc32 = crc32fast::Hasher::new();
+                crc32.update(&manifest);
+
+                let header = zip.start_entry(MANIFEST_PATH, manifest.len() as u64);
+                let descriptor = zip.finish_entry(crc32.finalize());
+                for part in [header, manifest, descriptor] {
+                    if send_part(&mut tx, part).await.is_err() {
+                        return;
+                    }
+                }
+            }
+
+            let _ = tx.send(Ok(zip.finish())).await;
+        });
+
+        rx
+    }
+
+    /// Requests the entry and waits for its first part, so nothing is written for unavailable entries
+    async fn open_archive_entry(
+        &self,
+        seed: &str,
+        requester: Option<ConnectionId>,
+        entry: &ArchiveEntry,
+    ) -> Result<(RelayRx, Option<bytes::Bytes>), TransferError> {
+        let request = self
+            .request_file(entry.id, seed.to_owned(), None, requester)
+            .await
+            .map_err(|_| TransferError::Unavailable(entry.id))?;
+        let mut data = request.data;
+
+        match tokio::time::timeout(self.relay.request_timeout(), data.next()).await {
+            Ok(Some(Ok(part))) => Ok((data, Some(part))),
+            Ok(None) => Ok((data, None)),
+            Ok(Some(Err(e))) => Err(e),
+            Err(_) => {
+                // Owner must not start the upload nobody waits for anymore
+                if let Some(request_id) = request.request_id {
+                    self.cancel_request(seed, &request_id).await;
+                }
+                Err(TransferError::Unavailable(entry.id))
+            }
+        }
+    }
+
+    async fn cancel_request(&self, seed: &str, request_id: &Uuid) {
+        let session = match decode_seed(seed) {
+            Ok(seed) => self.sessions.read().await.get(&seed).cloned(),
+            Err(_) => None,
+        };
+
+        if let Some(session) = session {
+            session.write().await.cancel_request(request_id);
+        }
+    }
+
+    async fn write_archive_entry(
+        &self,
+        zip: &mut ZipWriter,
+        tx: &mut RelayTx,
+        entry: &ArchiveEntry,
+        mut data: RelayRx,
+        first: Option<bytes::Bytes>,
+    ) -> Result<(), TransferError> {
+        send_part(tx, zip.start_entry(&entry.path, entry.size as u64)).await?;
+
+        let mut crc32 = crc32fast::Hasher::new();
+        let mut next = first.map(Ok);
+        while let Some(part) = next {
+            let part = part?;
+            crc32.update(&part);
+            send_part(tx, part).await?;
+            next = data.next().await;
+        }
+
+        send_part(tx, zip.finish_entry(crc32.finalize())).await
+    }
+}
+
+async fn send_part(tx: &mut RelayTx, part: bytes::Bytes) -> Result<(), TransferError> {
+    tx.send(Ok(part)).await.map_err(|_| TransferError::ReceiversGone)
+}
+
+/// Makes a valid archive path from the file name which doesn't collide with other paths
+fn unique_path(paths: &mut std::collections::HashSet<String>, name: &str) -> String {
+    let name = name.replace(['/', '\\'], "_");
+    let name = if matches!(name.as_str(), "" | "." | "..") {
+        "file".to_owned()
+    } else {
+        name
+    };
+
+    let mut path = name.clone();
+    let mut index = 1;
+    while !paths.insert(path.clone()) {
+        path = match name.rfind('.') {
+            Some(dot) if dot > 0 => format!("{} ({}){}", &name[..dot], index, &name[dot..]),
+            _ => format!("{} ({})", name, index),
+        };
+        index += 1;
+    }
+
+    path
+}
+
+const ARCHIVE_NAME: &str = "fbox";
+const MANIFEST_PATH: &str = "MANIFEST.txt";
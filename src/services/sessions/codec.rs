@@ -0,0 +1,106 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use warp::ws;
+
+/// Wire format of websocket messages
+pub trait Codec: Clone + Send + Sync + 'static {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<ws::Message, CodecError>;
+
+    /// Returns `None` for frames which don't carry messages in this format, e.g. pings
+    fn decode<T: DeserializeOwned>(&self, message: &ws::Message) -> Option<Result<T, CodecError>>;
+}
+
+/// JSON in text frames, used when the client doesn't request a subprotocol
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<ws::Message, CodecError> {
+        Ok(ws::Message::text(serde_json::to_string(message)?))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &ws::Message) -> Option<Result<T, CodecError>> {
+        let text = message.to_str().ok()?;
+        Some(serde_json::from_str(text).map_err(CodecError::from))
+    }
+}
+
+/// MessagePack in binary frames, structs are encoded as maps to keep the JSON message shapes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<ws::Message, CodecError> {
+        Ok(ws::Message::binary(rmp_serde::to_vec_named(message)?))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &ws::Message) -> Option<Result<T, CodecError>> {
+        if !message.is_binary() {
+            return None;
+        }
+        Some(rmp_serde::from_read_ref(message.as_bytes()).map_err(CodecError::from))
+    }
+}
+
+/// Codec selected with the `Sec-WebSocket-Protocol` header
+#[derive(Debug, Clone, Copy)]
+pub enum NegotiatedCodec {
+    Json(JsonCodec),
+    MessagePack(MessagePackCodec),
+}
+
+impl NegotiatedCodec {
+    /// Picks the first supported subprotocol from the header value,
+    /// returns the codec and the subprotocol to confirm in the response
+    pub fn negotiate(requested: Option<&str>) -> (Self, Option<&'static str>) {
+        let protocol = requested
+            .into_iter()
+            .flat_map(|requested| requested.split(','))
+            .map(str::trim)
+            .find_map(|protocol| match protocol {
+                JSON_PROTOCOL => Some((NegotiatedCodec::Json(JsonCodec), JSON_PROTOCOL)),
+                MESSAGE_PACK_PROTOCOL => Some((NegotiatedCodec::MessagePack(MessagePackCodec), MESSAGE_PACK_PROTOCOL)),
+                _ => None,
+            });
+
+        match protocol {
+            Some((codec, protocol)) => (codec, Some(protocol)),
+            None => (NegotiatedCodec::Json(JsonCodec), None),
+        }
+    }
+}
+
+impl Default for NegotiatedCodec {
+    fn default() -> Self {
+        NegotiatedCodec::Json(JsonCodec)
+    }
+}
+
+impl Codec for NegotiatedCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> Result<ws::Message, CodecError> {
+        match self {
+            NegotiatedCodec::Json(codec) => codec.encode(message),
+            NegotiatedCodec::MessagePack(codec) => codec.encode(message),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, message: &ws::Message) -> Option<Result<T, CodecError>> {
+        match self {
+            NegotiatedCodec::Json(codec) => codec.decode(message),
+            NegotiatedCodec::MessagePack(codec) => codec.decode(message),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+    #[error("{0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[error("{0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+}
+
+const JSON_PROTOCOL: &str = "fbox.json";
+const MESSAGE_PACK_PROTOCOL: &str = "fbox.msgpack";
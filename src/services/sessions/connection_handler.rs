@@ -1,24 +1,892 @@
-use bip39::{Language, Mnemonic, MnemonicType};
-use bytes::Buf;
-use futures::{Stream, StreamExt};
+use bip39::Mnemonic;
+use futures::StreamExt;
 use uuid::Uuid;
-use warp::ws::WebSocket;
 
-use super::session::{Connection, Session};
-use super::websocket::ConnectionId;
-use super::websocket::Event;
-use super::SessionService;
+use super::hash::FileHash;
+use super::session::*;
+use super::upload::InbandUploads;
+use super::websocket::{ConnectionId, Event, ProtocolVersion};
+use super::{encode_seed, zip, SessionService, TransferError, MAX_BUNDLE_ENTRY_COUNT, MAX_FILE_COUNT};
 use crate::prelude::*;
 
+/// State of the connection from the socket open to its close
+#[derive(Debug)]
+pub enum ConnectionState {
+    /// Peer can be paired with its phrase
+    Pending,
+    /// Peer is a member of the session
+    Paired(ArcRwLock<Session>),
+    /// Peer doesn't handle requests anymore and is removed once the handler is closed
+    Closing(Option<ArcRwLock<Session>>),
+}
+
+/// Handles the events of a single connection
 pub struct ConnectionHandler {
     session_service: Arc<SessionService>,
     conn: Arc<Connection>,
     mnemonic: Mnemonic,
-    session: Option<ArcRwLock<Session>>,
+    profile: PeerProfile,
+    /// Peer is served in process, like the Telegram chat, rather than by a device
+    local: bool,
+    state: ConnectionState,
+    uploads: InbandUploads,
 }
 
 impl ConnectionHandler {
-    pub fn new(session_service: Arc<SessionService>, conn: Arc<Connection>) -> Self {}
+    /// Registers the connection as pending and greets the peer with its phrase and what the server supports
+    pub async fn new(session_service: Arc<SessionService>, conn: Arc<Connection>, user_agent: Option<String>, local: bool) -> Self {
+        let profile = PeerProfile {
+            user_agent,
+            ..Default::default()
+        }
+        .sanitized();
+        let mnemonic = session_service
+            .create_pending_connection(conn.clone(), profile.clone(), local)
+            .await;
+
+        conn.send_external(&WsResponse::Created {
+            phrase: mnemonic.phrase().to_owned(),
+            versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            capabilities: session_service.capabilities(),
+        });
+
+        Self {
+            session_service,
+            conn,
+            mnemonic,
+            profile,
+            local,
+            state: ConnectionState::Pending,
+            uploads: Default::default(),
+        }
+    }
+
+    #[inline]
+    pub fn is_closing(&self) -> bool {
+        matches!(self.state, ConnectionState::Closing(_))
+    }
+
+    pub async fn handle_event(&mut self, event: Event<InternalMessage, WsRequestEnvelope>) {
+        if self.is_closing() {
+            return;
+        }
+
+        match event {
+            Event::External(WsRequestEnvelope { request_id, request }) => {
+                let response = match self.handle_request(request).await {
+                    Ok(()) => WsResponse::Ack { request_id },
+                    Err(code) => WsResponse::Error {
+                        request_id,
+                        code,
+                        message: code.to_string(),
+                    },
+                };
+                self.conn.send_external(&response);
+            }
+            Event::Invalid(invalid) => self.conn.send_external(&WsResponse::Error {
+                request_id: invalid.request_id,
+                code: if invalid.unknown_type {
+                    ErrorCode::UnknownType
+                } else {
+                    ErrorCode::InvalidRequest
+                },
+                message: invalid.message,
+            }),
+            Event::Data(frame) => {
+                if let Err(code) = self.uploads.push(frame) {
+                    self.conn.send_external(&WsResponse::Error {
+                        request_id: None,
+                        code,
+                        message: code.to_string(),
+                    });
+                }
+            }
+            Event::Internal(InternalMessage::SessionCreated(session)) => self.state = ConnectionState::Paired(session),
+            Event::Internal(InternalMessage::SessionExpired) => {
+                self.conn.send_external(&WsResponse::SessionExpired);
+                // Connection was already drained from the expired session
+                self.state = ConnectionState::Closing(None);
+            }
+            Event::Internal(InternalMessage::Kicked) => {
+                self.conn.send_external(&WsResponse::Kicked);
+                // Connection was already evicted by the host
+                self.state = ConnectionState::Closing(None);
+            }
+        }
+    }
+
+    /// Removes the connection from the pending peers and its session
+    pub async fn close(self) {
+        let queue = self.conn.queue_stats();
+        log::debug!(
+            "connection {} closed, last latency: {:?}, queue depth: {}, max depth: {}, dropped: {}",
+            self.conn.id(),
+            self.conn.latency(),
+            queue.depth,
+            queue.max_depth,
+            queue.dropped
+        );
+
+        let session = match self.state {
+            ConnectionState::Pending => None,
+            ConnectionState::Paired(session) => Some(session),
+            ConnectionState::Closing(session) => session,
+        };
+        self.session_service.remove_connection(self.conn, self.mnemonic, session).await
+    }
+
+    /// Handles the command of the peer, every command is answered with `Ack` or `Error`
+    async fn handle_request(&mut self, request: WsRequest) -> Result<(), ErrorCode> {
+        match request {
+            WsRequest::Hello { version, profile } => self.hello(version, profile).await,
+            WsRequest::UpdateProfile(profile) => self.update_profile(profile).await,
+            WsRequest::Connect { phrase } => self.connect(phrase).await,
+            WsRequest::AddFile {
+                id,
+                name,
+                mime_type,
+                size,
+                hash,
+                persist,
+            } => self.add_file(id, name, mime_type, size, hash, persist).await,
+            WsRequest::RemoveFile { id } => self.remove_file(id).await,
+            WsRequest::AddBundle { id, name, entries } => self.add_bundle(id, name, entries).await,
+            WsRequest::Resume { token } => self.resume(token).await,
+            WsRequest::CreateInvite => self.create_invite().await,
+            WsRequest::StartUpload { id, request_id } => self.start_upload(id, request_id).await,
+            WsRequest::RtcOffer {
+                connection_id,
+                transfer_id,
+                id,
+                sdp,
+            } => self.rtc_offer(connection_id, transfer_id, id, sdp).await,
+            WsRequest::RtcAnswer {
+                connection_id,
+                transfer_id,
+                sdp,
+            } => self.rtc_answer(connection_id, transfer_id, sdp).await,
+            WsRequest::RtcCandidate {
+                connection_id,
+                transfer_id,
+                candidate,
+            } => self.rtc_candidate(connection_id, transfer_id, candidate).await,
+            WsRequest::RtcClose {
+                connection_id,
+                transfer_id,
+                reason,
+            } => self.rtc_close(connection_id, transfer_id, reason).await,
+            WsRequest::KickPeer { connection_id } => self.kick_peer(connection_id).await,
+            WsRequest::LockSession { locked } => self.lock_session(locked).await,
+            WsRequest::TransferHost { connection_id } => self.transfer_host(connection_id).await,
+        }
+    }
+
+    async fn hello(&mut self, version: ProtocolVersion, profile: Option<PeerProfile>) -> Result<(), ErrorCode> {
+        if !SUPPORTED_PROTOCOL_VERSIONS.contains(&version) {
+            return Err(ErrorCode::UnsupportedVersion);
+        }
+        self.conn.set_version(version);
+        self.conn.send_external(&WsResponse::Hello {
+            versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            capabilities: self.session_service.capabilities(),
+        });
+
+        match profile {
+            Some(profile) => self.update_profile(profile).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Announces the new profile to the session, the user agent of the request is kept if it's missing
+    async fn update_profile(&mut self, profile: PeerProfile) -> Result<(), ErrorCode> {
+        let profile = PeerProfile {
+            user_agent: profile.user_agent.or_else(|| self.profile.user_agent.take()),
+            ..profile
+        }
+        .sanitized();
+        self.profile = profile.clone();
+
+        match &self.state {
+            ConnectionState::Pending => self.session_service.update_pending_profile(self.mnemonic.phrase(), profile).await,
+            ConnectionState::Paired(session) => session.write().await.update_profile(self.conn.id(), profile),
+            ConnectionState::Closing(_) => {}
+        }
+        Ok(())
+    }
+
+    /// Pulls the pending peer with the phrase into the session, the session is created if needed
+    ///
+    /// Pending connection joins the existing session instead if the phrase is its invite.
+    async fn connect(&mut self, peer_phrase: String) -> Result<(), ErrorCode> {
+        const MIN_PHRASE_LEN: usize = 6 * 3 + 5; // 6 words with 3 letters + 5 spaces
+        const MAX_PHRASE_LEN: usize = 6 * 8 + 5; // 6 words with 8 letters + 5 spaces
+        if peer_phrase.len() < MIN_PHRASE_LEN || peer_phrase.len() > MAX_PHRASE_LEN || peer_phrase == self.mnemonic.phrase() {
+            return Err(ErrorCode::PeerNotFound);
+        }
+
+        let service = &self.session_service;
+
+        // Pending peer stays pairable if the session is locked
+        if let ConnectionState::Paired(session) = &self.state {
+            if session.read().await.locked {
+                return Err(ErrorCode::SessionLocked);
+            }
+        }
+
+        let PendingConnection {
+            connection: peer,
+            profile: peer_profile,
+            local: peer_local,
+            ..
+        } = match service.remove_pending_peer(&peer_phrase).await {
+            Some(entry) => entry,
+            None if matches!(self.state, ConnectionState::Pending) => return self.join(&peer_phrase).await,
+            None => return Err(ErrorCode::PeerNotFound),
+        };
+
+        // If session exists
+        if let ConnectionState::Paired(session) = &self.state {
+            let (connected, peer_list) = {
+                let mut session = session.write().await;
+                session.join(peer.clone(), peer_profile);
+                let connected = session.connected(peer.id(), service.resume_tokens.issue(&session.seed, peer.id()));
+                (connected, session.peer_list())
+            };
+
+            peer.send_internal(InternalMessage::SessionCreated(session.clone()));
+            peer.send_external(&connected);
+            peer.send_external(&peer_list);
+            return Ok(());
+        }
+
+        // Remove host from pending peer
+        service.remove_pending_peer(self.mnemonic.phrase()).await;
+
+        // Create new session
+        let mut session = Session::new(&self.mnemonic, &service.seed_password, self.conn.clone(), self.profile.clone());
+        let seed = session.seed.clone();
+        session.join_phrase = service.create_invite(&seed, None).await;
+
+        // Add peer to connections
+        session.connections.insert(peer.id(), peer.clone());
+        session.profiles.insert(peer.id(), peer_profile);
+
+        // Local peer pairs on behalf of its chat, so the device which shows the phrase moderates the session
+        if self.local && !peer_local {
+            session.host = peer.id();
+        }
+
+        let peer_connected = session.connected(peer.id(), service.resume_tokens.issue(&seed, peer.id()));
+        let connected = session.connected(self.conn.id(), service.resume_tokens.issue(&seed, self.conn.id()));
+        let peer_list = session.peer_list();
+        let session = Arc::new(RwLock::new(session));
+
+        // Add new session to self sessions
+        service.sessions.write().await.insert(seed, session.clone());
+
+        // Send messages
+        peer.send_internal(InternalMessage::SessionCreated(session.clone()));
+        peer.send_external(&peer_connected);
+        peer.send_external(&peer_list);
+        self.conn.send_external(&connected);
+        self.conn.send_external(&peer_list);
+
+        self.state = ConnectionState::Paired(session);
+        Ok(())
+    }
+
+    /// Joins the session with its join phrase or invite
+    async fn join(&mut self, phrase: &str) -> Result<(), ErrorCode> {
+        let service = &self.session_service;
+        let session = service.take_invite(phrase).await?;
+
+        let (connected, peer_list) = {
+            let mut session = session.write().await;
+            // Session could be locked after the invite was taken
+            if session.locked {
+                return Err(ErrorCode::SessionLocked);
+            }
+            session.join(self.conn.clone(), self.profile.clone());
+            session.touch();
+            let connected = session.connected(self.conn.id(), service.resume_tokens.issue(&session.seed, self.conn.id()));
+            (connected, session.peer_list())
+        };
+        log::debug!("connection {} joined with invite", self.conn.id());
+
+        // Joined peer is no longer pairable with its own phrase
+        service.remove_pending_peer(self.mnemonic.phrase()).await;
+
+        self.conn.send_external(&connected);
+        self.conn.send_external(&peer_list);
+        self.state = ConnectionState::Paired(session);
+        Ok(())
+    }
+
+    /// Sends the fresh one-time phrase to the peer, it expires like pairing phrases
+    async fn create_invite(&mut self) -> Result<(), ErrorCode> {
+        let seed = self.session()?.read().await.seed.clone();
+
+        let ttl = self.session_service.session_settings.pending_ttl();
+        let phrase = self.session_service.create_invite(&seed, Some(ttl)).await;
+        let expires_at = chrono::Utc::now() + chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::zero());
+
+        self.conn.send_external(&WsResponse::InviteCreated { phrase, expires_at });
+        Ok(())
+    }
+
+    async fn add_file(
+        &mut self,
+        id: Uuid,
+        name: String,
+        mime_type: String,
+        size: usize,
+        hash: Option<FileHash>,
+        persist: bool,
+    ) -> Result<(), ErrorCode> {
+        let session_handle = self.session()?;
+        let mut session = session_handle.write().await;
+
+        if persist && self.session_service.storage.is_none() {
+            return Err(ErrorCode::StorageUnavailable);
+        }
+
+        if session.item_count() + 1 >= MAX_FILE_COUNT {
+            return Err(ErrorCode::FileCountLimitReached);
+        }
+
+        if session.contains_id(&id) {
+            return Err(ErrorCode::FileAlreadyExists);
+        }
+
+        if matches!(&hash, Some(hash) if !hash.is_valid()) {
+            return Err(ErrorCode::InvalidFileHash);
+        }
+
+        let file_info = FileInfo {
+            id,
+            name,
+            mime_type,
+            size,
+            hash,
+            expires_at: None,
+            available: true,
+            connection_id: self.conn.id(),
+        };
+        session.files.insert(file_info.id, file_info.clone());
+        session.touch();
+        session.broadcast_external(&WsResponse::FileAdded(file_info));
+
+        if persist {
+            let seed = encode_seed(&session.seed);
+            drop(session);
+            self.session_service.store_file(id, seed, session_handle.clone()).await;
+        }
+        Ok(())
+    }
+
+    /// Peers remove their own files and bundles, the host can remove any of them
+    async fn remove_file(&mut self, id: Uuid) -> Result<(), ErrorCode> {
+        let mut session = self.session()?.write().await;
+
+        let owner = match (session.files.get(&id), session.bundles.get(&id)) {
+            (Some(file), _) => file.connection_id,
+            (None, Some(bundle)) => bundle.connection_id,
+            (None, None) => return Err(ErrorCode::FileNotFound),
+        };
+        if owner != self.conn.id() && !session.is_host(self.conn.id()) {
+            return Err(ErrorCode::NotAllowed);
+        }
+
+        if session.files.remove(&id).is_some() {
+            session.pending_requests.retain(|_, request| request.file_id != id);
+            session.touch();
+            session.broadcast_external(&WsResponse::FileRemoved { id });
+
+            if let (Some(stored_file), Some(storage)) = (session.stored_files.remove(&id), self.session_service.storage.clone()) {
+                tokio::spawn(async move { storage.remove(stored_file.key).await });
+            }
+        } else if session.remove_bundle(&id).is_some() {
+            session.touch();
+            session.broadcast_external(&WsResponse::BundleRemoved { id });
+        } else {
+            return Err(ErrorCode::FileNotFound);
+        }
+        Ok(())
+    }
+
+    async fn add_bundle(&mut self, id: Uuid, name: String, entries: Vec<BundleEntry>) -> Result<(), ErrorCode> {
+        let mut session = self.session()?.write().await;
+
+        if session.item_count() + 1 >= MAX_FILE_COUNT {
+            return Err(ErrorCode::FileCountLimitReached);
+        }
+
+        let mut ids = std::collections::HashSet::new();
+        if session.contains_id(&id) || entries.iter().any(|entry| !ids.insert(entry.id) || session.contains_id(&entry.id)) {
+            return Err(ErrorCode::FileAlreadyExists);
+        }
+
+        let mut paths = std::collections::HashSet::new();
+        if entries.is_empty()
+            || entries.len() > MAX_BUNDLE_ENTRY_COUNT
+            || ids.contains(&id)
+            || entries
+                .iter()
+                .any(|entry| !zip::is_valid_path(&entry.path) || !paths.insert(&entry.path))
+        {
+            return Err(ErrorCode::InvalidBundle);
+        }
+
+        let bundle_info = BundleInfo {
+            id,
+            name,
+            entries,
+            available: true,
+            connection_id: self.conn.id(),
+        };
+        session.bundles.insert(bundle_info.id, bundle_info.clone());
+        session.touch();
+        session.broadcast_external(&WsResponse::BundleAdded(bundle_info));
+        Ok(())
+    }
+
+    /// Reattaches the pending connection to the session it was suspended in
+    async fn resume(&mut self, token: String) -> Result<(), ErrorCode> {
+        let service = &self.session_service;
+        let resumed = match (&self.state, service.resume_tokens.verify(&token)) {
+            (ConnectionState::Pending, Some((seed, connection_id))) => {
+                service.resume_session(&seed, self.conn.with_id(connection_id)).await
+            }
+            _ => None,
+        };
+
+        let (session, conn, response) = match resumed {
+            Some(resumed) => resumed,
+            None => return Err(ErrorCode::ResumeFailed),
+        };
+
+        // Resumed peer is no longer pairable with its own phrase
+        service.remove_pending_peer(self.mnemonic.phrase()).await;
+
+        let peer_list = session.read().await.peer_list();
+        self.conn = conn;
+        self.state = ConnectionState::Paired(session);
+        self.conn.send_external(&response);
+        self.conn.send_external(&peer_list);
+        Ok(())
+    }
+
+    /// Answers the file request with data frames, the relay pulls chunks as the credits are returned
+    async fn start_upload(&mut self, id: Uuid, request_id: Uuid) -> Result<(), ErrorCode> {
+        let (seed, size) = {
+            let session = self.session()?.read().await;
+
+            let file = match session.find_file(&id) {
+                Some(file) if file.connection_id == self.conn.id() => file,
+                _ => return Err(ErrorCode::FileNotFound),
+            };
+
+            match session.pending_requests.get(&request_id) {
+                Some(request) if request.file_id == id && request.state == RequestState::Requested => {
+                    let size = request.range.map(|range| range.len()).unwrap_or(file.size);
+                    (encode_seed(&session.seed), size)
+                }
+                _ => return Err(ErrorCode::UploadNotFound),
+            }
+        };
+
+        let credits = self.session_service.relay_capacity();
+        let data = match self.uploads.start(request_id, size, credits) {
+            Some(data) => data,
+            None => return Err(ErrorCode::UploadNotFound),
+        };
+
+        // Credits are returned in batches as the relay consumes chunks
+        let owner = self.conn.clone();
+        let mut consumed = 0;
+        let data = data.inspect(move |_| {
+            consumed += 1;
+            if consumed >= (credits / 2).max(1) {
+                owner.send_external(&WsResponse::UploadCredits {
+                    request_id,
+                    credits: consumed,
+                });
+                consumed = 0;
+            }
+        });
+
+        let service = self.session_service.clone();
+        let owner = self.conn.clone();
+        tokio::spawn(async move {
+            // Other failures are reported by the upload itself
+            if let Err(e @ TransferError::RequestNotFound) = service.upload_file(id, request_id, seed, size, data).await {
+                owner.send_external(&WsResponse::TransferFailed {
+                    id,
+                    request_id,
+                    reason: e.to_string(),
+                });
+            }
+        });
+
+        self.conn.send_external(&WsResponse::UploadCredits { request_id, credits });
+        Ok(())
+    }
+
+    async fn rtc_offer(&mut self, connection_id: ConnectionId, transfer_id: Uuid, id: Uuid, sdp: String) -> Result<(), ErrorCode> {
+        let offer = WsResponse::RtcOffer {
+            connection_id: self.conn.id(),
+            transfer_id,
+            id,
+            sdp,
+        };
+        self.relay_signal(connection_id, Some(id), &offer).await
+    }
+
+    async fn rtc_answer(&mut self, connection_id: ConnectionId, transfer_id: Uuid, sdp: String) -> Result<(), ErrorCode> {
+        let answer = WsResponse::RtcAnswer {
+            connection_id: self.conn.id(),
+            transfer_id,
+            sdp,
+        };
+        self.relay_signal(connection_id, None, &answer).await
+    }
+
+    async fn rtc_candidate(&mut self, connection_id: ConnectionId, transfer_id: Uuid, candidate: IceCandidate) -> Result<(), ErrorCode> {
+        let candidate = WsResponse::RtcCandidate {
+            connection_id: self.conn.id(),
+            transfer_id,
+            candidate,
+        };
+        self.relay_signal(connection_id, None, &candidate).await
+    }
+
+    async fn rtc_close(&mut self, connection_id: ConnectionId, transfer_id: Uuid, reason: Option<String>) -> Result<(), ErrorCode> {
+        let close = WsResponse::RtcClose {
+            connection_id: self.conn.id(),
+            transfer_id,
+            reason,
+        };
+        self.relay_signal(connection_id, None, &close).await
+    }
+
+    /// Forwards WebRTC signalling to the other peer of the session, the server never sees the file data
+    ///
+    /// Offers are only accepted for available files of the receiving peer.
+    async fn relay_signal(&self, connection_id: ConnectionId, file_id: Option<Uuid>, message: &WsResponse) -> Result<(), ErrorCode> {
+        if !self.session_service.rtc.enabled {
+            return Err(ErrorCode::RtcUnavailable);
+        }
+
+        let session = self.session()?.read().await;
+
+        let peer = match session.connections.get(&connection_id) {
+            Some(peer) if peer.id() != self.conn.id() => peer,
+            _ => return Err(ErrorCode::PeerNotFound),
+        };
+
+        // Legacy clients don't know the signalling messages
+        if peer.version() < PROTOCOL_V2 {
+            return Err(ErrorCode::RtcUnavailable);
+        }
+
+        if let Some(id) = file_id {
+            match session.find_file(&id) {
+                Some(file) if file.connection_id == connection_id && file.available => {}
+                _ => return Err(ErrorCode::FileNotFound),
+            }
+        }
+
+        peer.send_external(message);
+        Ok(())
+    }
+
+    /// Evicts the peer right away, so it can't resume with its token, and replaces the join phrase, so it can't rejoin
+    async fn kick_peer(&mut self, connection_id: ConnectionId) -> Result<(), ErrorCode> {
+        let session_handle = self.session()?.clone();
+        let (seed, old_phrase) = {
+            let mut session = session_handle.write().await;
+            ensure_host(&session, self.conn.id())?;
+
+            if connection_id == self.conn.id() {
+                return Err(ErrorCode::InvalidRequest);
+            }
+            if !session.profiles.contains_key(&connection_id) {
+                return Err(ErrorCode::PeerNotFound);
+            }
+
+            let peer = session.connections.get(&connection_id).cloned();
+            session.evict_connection(connection_id);
+            session.touch();
+            log::debug!("connection {} was kicked by the host", connection_id);
+
+            if let Some(peer) = peer {
+                peer.send_internal(InternalMessage::Kicked);
+            }
+            (session.seed.clone(), session.join_phrase.clone())
+        };
+
+        // Invites are not locked while the session is
+        let service = &self.session_service;
+        service.remove_invite(&old_phrase).await;
+        let join_phrase = service.create_invite(&seed, None).await;
+
+        let mut session = session_handle.write().await;
+        if session.join_phrase == old_phrase {
+            session.set_join_phrase(join_phrase);
+        } else {
+            // Concurrent kick has already replaced it
+            drop(session);
+            service.remove_invite(&join_phrase).await;
+        }
+        Ok(())
+    }
+
+    async fn lock_session(&mut self, locked: bool) -> Result<(), ErrorCode> {
+        let mut session = self.session()?.write().await;
+        ensure_host(&session, self.conn.id())?;
+
+        session.set_locked(locked);
+        Ok(())
+    }
+
+    /// Only connected peers can become the host
+    async fn transfer_host(&mut self, connection_id: ConnectionId) -> Result<(), ErrorCode> {
+        let mut session = self.session()?.write().await;
+        ensure_host(&session, self.conn.id())?;
+
+        if connection_id == self.conn.id() {
+            return Err(ErrorCode::InvalidRequest);
+        }
+        if !session.connections.contains_key(&connection_id) {
+            return Err(ErrorCode::PeerNotFound);
+        }
+
+        session.set_host(connection_id);
+        Ok(())
+    }
+
+    /// Session of the paired peer, requests which need it fail otherwise
+    fn session(&self) -> Result<&ArcRwLock<Session>, ErrorCode> {
+        match &self.state {
+            ConnectionState::Paired(session) => Ok(session),
+            _ => Err(ErrorCode::SessionNotFound),
+        }
+    }
+}
+
+fn ensure_host(session: &Session, connection_id: ConnectionId) -> Result<(), ErrorCode> {
+    if session.is_host(connection_id) {
+        Ok(())
+    } else {
+        Err(ErrorCode::NotAllowed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{self, TestPeer};
+    use super::*;
+
+    /// Guest shows its phrase and the host connects with it, returns the `Connected` content of the guest
+    async fn pair(session_service: &Arc<SessionService>) -> (TestPeer, TestPeer, serde_json::Value) {
+        let mut guest = TestPeer::connect(session_service);
+        let phrase = guest.recv("created").await["phrase"].as_str().unwrap().to_owned();
+
+        let mut host = TestPeer::connect(session_service);
+        host.send(&WsRequest::Connect { phrase });
+        let host_connected = host.recv("connected").await;
+        let guest_connected = guest.recv("connected").await;
+        assert_eq!(guest_connected["seed"], host_connected["seed"]);
+
+        (host, guest, guest_connected)
+    }
+
+    fn add_file(peer: &TestPeer, id: Uuid) {
+        peer.send(&WsRequest::AddFile {
+            id,
+            name: "notes.txt".to_owned(),
+            mime_type: "text/plain".to_owned(),
+            size: 5,
+            hash: None,
+            persist: false,
+        });
+    }
+
+    #[tokio::test]
+    async fn legacy_peer_negotiates_version() {
+        let session_service = testing::session_service();
+        let mut peer = TestPeer::connect_legacy(&session_service);
+
+        let created = peer.recv("created").await;
+        assert_eq!(created["versions"], serde_json::json!(SUPPORTED_PROTOCOL_VERSIONS));
+        assert!(created["capabilities"].is_object());
+
+        peer.send(&WsRequest::Hello {
+            version: 99,
+            profile: None,
+        });
+        assert_eq!(peer.recv("error").await["code"], "unsupported_version");
+
+        peer.send(&WsRequest::Hello {
+            version: PROTOCOL_V1,
+            profile: None,
+        });
+        assert_eq!(peer.recv("hello").await["versions"], serde_json::json!(SUPPORTED_PROTOCOL_VERSIONS));
+    }
+
+    #[tokio::test]
+    async fn pending_peer_requires_session() {
+        let session_service = testing::session_service();
+        let mut peer = TestPeer::connect(&session_service);
+
+        add_file(&peer, Uuid::new_v4());
+        assert_eq!(peer.recv("error").await["code"], "session_not_found");
+
+        peer.send(&WsRequest::Connect {
+            phrase: "abandon ability able about above absent".to_owned(),
+        });
+        assert_eq!(peer.recv("error").await["code"], "peer_not_found");
+    }
+
+    #[tokio::test]
+    async fn connect_pairs_both_peers() {
+        let session_service = testing::session_service();
+        let (mut host, mut guest, connected) = pair(&session_service).await;
+
+        let peers = guest.recv("peer_list").await["peers"].clone();
+        assert_eq!(peers.as_array().unwrap().len(), 2);
+        host.recv("ack").await;
+
+        // Host pulls more pending peers into its session
+        let mut other = TestPeer::connect(&session_service);
+        let phrase = other.recv("created").await["phrase"].as_str().unwrap().to_owned();
+        host.send(&WsRequest::Connect { phrase });
+        let joined = other.recv("connected").await;
+        assert_eq!(joined["seed"], connected["seed"]);
+    }
+
+    #[tokio::test]
+    async fn device_hosts_session_paired_by_local_peer() {
+        let session_service = testing::session_service();
+        let mut device = TestPeer::connect_device(&session_service);
+        let phrase = device.recv("created").await["phrase"].as_str().unwrap().to_owned();
+
+        let mut chat = TestPeer::connect(&session_service);
+        chat.send(&WsRequest::Connect { phrase });
+        let chat_connected = chat.recv("connected").await;
+        let device_connected = device.recv("connected").await;
+        assert_eq!(device_connected["host"], device_connected["connection_id"]);
+        assert_eq!(chat_connected["host"], device_connected["connection_id"]);
+
+        let connection_id = chat_connected["connection_id"].as_u64().unwrap() as ConnectionId;
+        chat.send(&WsRequest::LockSession { locked: true });
+        assert_eq!(chat.recv("error").await["code"], "not_allowed");
+        device.send(&WsRequest::KickPeer { connection_id });
+        chat.recv("kicked").await;
+    }
+
+    #[tokio::test]
+    async fn join_phrase_adds_peer_to_session() {
+        let session_service = testing::session_service();
+        let (mut host, _guest, connected) = pair(&session_service).await;
+
+        let mut peer = TestPeer::connect(&session_service);
+        peer.send(&WsRequest::Connect {
+            phrase: connected["join_phrase"].as_str().unwrap().to_owned(),
+        });
+        let joined = peer.recv("connected").await;
+        assert_eq!(joined["seed"], connected["seed"]);
+        assert_eq!(host.recv("peer_joined").await["connection_id"], joined["connection_id"]);
+    }
+
+    #[tokio::test]
+    async fn files_are_broadcast_to_session() {
+        let session_service = testing::session_service();
+        let (mut host, mut guest, _) = pair(&session_service).await;
+
+        let id = Uuid::new_v4();
+        add_file(&guest, id);
+        assert_eq!(host.recv("file_added").await["id"], id.to_string());
+        assert_eq!(guest.recv("file_added").await["id"], id.to_string());
+
+        add_file(&guest, id);
+        assert_eq!(guest.recv("error").await["code"], "file_already_exists");
+
+        guest.send(&WsRequest::RemoveFile { id });
+        assert_eq!(host.recv("file_removed").await["id"], id.to_string());
+        assert_eq!(guest.recv("file_removed").await["id"], id.to_string());
+
+        guest.send(&WsRequest::RemoveFile { id });
+        assert_eq!(guest.recv("error").await["code"], "file_not_found");
+    }
+
+    #[tokio::test]
+    async fn only_owner_or_host_removes_files() {
+        let session_service = testing::session_service();
+        let (mut host, mut guest, _) = pair(&session_service).await;
+
+        let host_file = Uuid::new_v4();
+        add_file(&host, host_file);
+        guest.recv("file_added").await;
+        guest.send(&WsRequest::RemoveFile { id: host_file });
+        assert_eq!(guest.recv("error").await["code"], "not_allowed");
+
+        let guest_file = Uuid::new_v4();
+        add_file(&guest, guest_file);
+        host.recv("file_added").await;
+        host.send(&WsRequest::RemoveFile { id: guest_file });
+        assert_eq!(guest.recv("file_removed").await["id"], guest_file.to_string());
+    }
+
+    #[tokio::test]
+    async fn kicked_peer_is_closed() {
+        let session_service = testing::session_service();
+        let (mut host, mut guest, connected) = pair(&session_service).await;
+
+        let connection_id = connected["connection_id"].as_u64().unwrap() as ConnectionId;
+        guest.send(&WsRequest::KickPeer { connection_id });
+        assert_eq!(guest.recv("error").await["code"], "not_allowed");
+
+        host.send(&WsRequest::KickPeer { connection_id });
+        assert_eq!(host.recv("peer_left").await["connection_id"], connection_id);
+        guest.recv("kicked").await;
+
+        // Closing connection doesn't handle requests anymore, its socket ends
+        assert!(guest.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn kick_replaces_join_phrase() {
+        let session_service = testing::session_service();
+        let (mut host, mut guest, connected) = pair(&session_service).await;
+
+        let connection_id = connected["connection_id"].as_u64().unwrap() as ConnectionId;
+        host.send(&WsRequest::KickPeer { connection_id });
+        let join_phrase = host.recv("join_phrase_changed").await["join_phrase"].clone();
+        assert_ne!(join_phrase, connected["join_phrase"]);
+        guest.recv("kicked").await;
+
+        let mut kicked = TestPeer::connect(&session_service);
+        kicked.send(&WsRequest::Connect {
+            phrase: connected["join_phrase"].as_str().unwrap().to_owned(),
+        });
+        assert_eq!(kicked.recv("error").await["code"], "peer_not_found");
+
+        let mut peer = TestPeer::connect(&session_service);
+        peer.send(&WsRequest::Connect {
+            phrase: join_phrase.as_str().unwrap().to_owned(),
+        });
+        assert_eq!(peer.recv("connected").await["join_phrase"], join_phrase);
+    }
+
+    #[tokio::test]
+    async fn closed_peer_is_suspended() {
+        let session_service = testing::session_service();
+        let (mut host, guest, connected) = pair(&session_service).await;
 
-    async fn connect(&self, peer_phrase: String) {}
+        drop(guest);
+        let peer = host.recv("peer_updated").await;
+        assert_eq!(peer["connection_id"], connected["connection_id"]);
+        assert_eq!(peer["online"], false);
+    }
 }
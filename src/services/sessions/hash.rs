@@ -0,0 +1,81 @@
+use sha2::Digest;
+
+use crate::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Digest algorithm token used in the `Repr-Digest` header
+    pub fn token(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha-256",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHash {
+    pub algorithm: HashAlgorithm,
+    /// Hex encoded digest
+    pub value: String,
+}
+
+impl FileHash {
+    pub fn is_valid(&self) -> bool {
+        const DIGEST_LEN: usize = 32; // both algorithms produce 256 bit digests
+        matches!(hex::decode(&self.value), Ok(digest) if digest.len() == DIGEST_LEN)
+    }
+
+    pub fn matches(&self, other: &FileHash) -> bool {
+        self.algorithm == other.algorithm && self.value.eq_ignore_ascii_case(&other.value)
+    }
+
+    /// Value for the `Repr-Digest` header
+    pub fn repr_digest(&self) -> Option<String> {
+        let digest = hex::decode(&self.value).ok()?;
+        Some(format!("{}=:{}:", self.algorithm.token(), base64::encode(digest)))
+    }
+}
+
+/// Incremental hasher for the relayed bytes
+pub enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> FileHash {
+        match self {
+            Hasher::Sha256(hasher) => FileHash {
+                algorithm: HashAlgorithm::Sha256,
+                value: hex::encode(hasher.finalize()),
+            },
+            Hasher::Blake3(hasher) => FileHash {
+                algorithm: HashAlgorithm::Blake3,
+                value: hex::encode(blake3::Hasher::finalize(&hasher).as_bytes()),
+            },
+        }
+    }
+}
@@ -1,253 +1,662 @@
+mod archive;
+mod codec;
+mod connection_handler;
+mod hash;
+mod range;
+mod relay;
+mod resume;
 mod session;
+mod storage;
+#[cfg(test)]
+pub mod testing;
+mod upload;
 mod websocket;
+mod zip;
 
 use bip39::{Language, Mnemonic, MnemonicType};
 use bytes::Buf;
-use futures::{Stream, StreamExt};
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{Sink, Stream, StreamExt};
 use uuid::Uuid;
-use warp::ws::WebSocket;
-
+use warp::ws::{self, WebSocket};
+
+pub use self::archive::ArchiveRequest;
+pub use self::codec::NegotiatedCodec;
+use self::connection_handler::ConnectionHandler;
+use self::hash::{FileHash, Hasher};
+pub use self::range::{ByteRange, RangeSpec};
+use self::relay::RelayReceiver;
+pub use self::relay::{RelayRx, TransferError};
+use self::resume::ResumeTokens;
 use self::session::*;
-use self::websocket::Event;
+pub use self::session::{DeviceType, PeerProfile, WsRequest, PROTOCOL_V2};
+use self::storage::Storage;
+pub use self::websocket::queue_metrics;
+use self::websocket::ConnectionId;
 use crate::prelude::*;
+use crate::settings::{HeartbeatSettings, QueueSettings, RelaySettings, RtcSettings, SessionSettings};
 
-pub type PendingConnections = RwLock<HashMap<Phrase, Arc<Connection>>>;
+pub type PendingConnections = RwLock<HashMap<Phrase, PendingConnection>>;
+pub type Invites = RwLock<HashMap<Phrase, Invite>>;
 pub type Sessions = RwLock<HashMap<Seed, ArcRwLock<Session>>>;
 
 pub struct SessionService {
     seed_password: String,
+    relay: RelaySettings,
+    session_settings: SessionSettings,
+    heartbeat: HeartbeatSettings,
+    queue: QueueSettings,
+    rtc: RtcSettings,
+    storage: Option<Arc<Storage>>,
+    resume_tokens: ResumeTokens,
     pending_connections: PendingConnections,
+    invites: Invites,
     sessions: Sessions,
 }
 
 impl SessionService {
-    pub fn new(settings: &Settings) -> Arc<Self> {
-        Arc::new(Self {
+    pub fn new(settings: &Settings) -> Result<Arc<Self>> {
+        let storage = match &settings.storage {
+            Some(storage) => Some(Arc::new(Storage::new(storage)?)),
+            None => None,
+        };
+
+        Ok(Arc::new(Self {
             seed_password: settings.password.clone(),
+            relay: settings.relay.clone(),
+            session_settings: settings.sessions.clone(),
+            heartbeat: settings.heartbeat.clone(),
+            queue: settings.queue.clone(),
+            rtc: settings.rtc.clone(),
+            storage,
+            resume_tokens: ResumeTokens::new(),
             pending_connections: Default::default(),
+            invites: Default::default(),
             sessions: Default::default(),
-        })
+        }))
     }
 
-    pub async fn request_file(&self, id: Uuid, seed: String) -> Option<(FileInfo, mpsc::UnboundedReceiver<bytes::Bytes>)> {
-        let seed = decode_seed(&seed).ok()?;
+    /// Periodically closes expired pairing phrases and sessions
+    pub async fn run_session_reaper(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(SESSION_REAPER_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let pending_ttl = self.session_settings.pending_ttl();
+            let expired_connections = {
+                let mut pending_connections = self.pending_connections.write().await;
+                let expired = pending_connections
+                    .iter()
+                    .filter(|(_, pending)| pending.created_at.elapsed() >= pending_ttl)
+                    .map(|(phrase, _)| phrase.clone())
+                    .collect::<Vec<_>>();
+
+                expired
+                    .into_iter()
+                    .filter_map(|phrase| pending_connections.remove(&phrase))
+                    .collect::<Vec<_>>()
+            };
+
+            for pending in expired_connections {
+                pending.connection.send_internal(InternalMessage::SessionExpired);
+            }
+
+            self.invites.write().await.retain(|_, invite| !invite.is_expired());
+
+            let sessions = self.sessions.read().await.values().cloned().collect::<Vec<_>>();
+            for session in sessions {
+                let (seed, stored_files) = {
+                    let mut session = session.write().await;
+                    if self.is_session_expired(&session) {
+                        // Handlers of drained connections skip the cleanup
+                        for (_, connection) in session.connections.drain() {
+                            connection.send_internal(InternalMessage::SessionExpired);
+                        }
+                        log::debug!("session expired");
+
+                        (session.seed.clone(), std::mem::take(&mut session.stored_files))
+                    } else {
+                        let grace = self.session_settings.resume_grace();
+                        let gone = session
+                            .suspended
+                            .iter()
+                            .filter(|(_, disconnected_at)| disconnected_at.elapsed() >= grace)
+                            .map(|(&connection_id, _)| connection_id)
+                            .collect::<Vec<_>>();
+                        for connection_id in gone {
+                            session.evict_connection(connection_id);
+                        }
+
+                        if !session.is_abandoned() {
+                            continue;
+                        }
+                        (session.seed.clone(), Default::default())
+                    }
+                };
+
+                self.remove_session(&seed).await;
+
+                if let Some(storage) = &self.storage {
+                    for stored_file in stored_files.values() {
+                        storage.remove(stored_file.key).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Idle timeout doesn't apply to sessions with stored files, they have their own TTL,
+    /// and to sessions with running uploads, which touch the session only once they end
+    fn is_session_expired(&self, session: &Session) -> bool {
+        let settings = &self.session_settings;
+        session.created_at.elapsed() >= settings.max_lifetime()
+            || (session.stored_files.is_empty() && !session.is_uploading() && session.last_activity.elapsed() >= settings.idle_ttl())
+    }
+
+    /// Periodically removes stored files with expired TTL
+    pub async fn run_storage_cleanup(self: Arc<Self>) {
+        let storage = match &self.storage {
+            Some(storage) => storage.clone(),
+            None => return,
+        };
+
+        let mut interval = tokio::time::interval(STORAGE_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let now = chrono::Utc::now();
+            let sessions = self.sessions.read().await.values().cloned().collect::<Vec<_>>();
+
+            for session in sessions {
+                let (expired, seed) = {
+                    let mut session = session.write().await;
+
+                    let expired = session
+                        .stored_files
+                        .iter()
+                        .filter(|(_, stored_file)| stored_file.expires_at <= now)
+                        .map(|(&id, stored_file)| (id, stored_file.key))
+                        .collect::<Vec<_>>();
+
+                    for (id, _) in &expired {
+                        session.stored_files.remove(id);
+                        session.files.remove(id);
+                        session.pending_requests.retain(|_, request| request.file_id != *id);
+                        session.broadcast_external(&WsResponse::FileRemoved { id: *id });
+                    }
+
+                    (expired, session.is_abandoned().then(|| session.seed.clone()))
+                };
+
+                for (_, key) in expired {
+                    storage.remove(key).await;
+                }
+
+                if let Some(seed) = seed {
+                    self.remove_session(&seed).await;
+                }
+            }
+        }
+    }
+
+    pub async fn request_file(
+        &self,
+        id: Uuid,
+        seed: String,
+        range: Option<RangeSpec>,
+        requester: Option<ConnectionId>,
+    ) -> Result<FileRequest, RequestError> {
+        let seed = decode_seed(&seed).map_err(|_| RequestError::SessionNotFound)?;
         log::debug!("decoded seed: {:?}", seed);
 
-        let session = self.sessions.read().await.get(&seed)?.clone();
+        let session = self.sessions.read().await.get(&seed).ok_or(RequestError::SessionNotFound)?.clone();
         log::debug!("found session");
 
         let mut session = session.write().await;
-        let file = session.files.get(&id)?.clone();
+        let file = session.find_file(&id).ok_or(RequestError::FileNotFound)?;
         log::debug!("found file");
+        session.touch();
+
+        let resolve_range = |range: RangeSpec| {
+            range
+                .resolve(file.size)
+                .ok_or(RequestError::RangeNotSatisfiable { size: file.size })
+        };
+
+        // Stored files are served without the owner
+        if let (Some(stored_file), Some(storage)) = (session.stored_files.get(&id), &self.storage) {
+            let range = range.map(resolve_range).transpose()?;
+            let data = storage.get(stored_file.key, file.size, range, self.relay_capacity());
+            log::debug!("found stored file");
+
+            return Ok(FileRequest {
+                file,
+                range,
+                request_id: None,
+                data,
+            });
+        }
 
-        let file_owner = session.connections.get(&file.connection_id)?.clone();
+        if !file.available {
+            return Err(RequestError::FileUnavailable);
+        }
+
+        let file_owner = session
+            .connections
+            .get(&file.connection_id)
+            .ok_or(RequestError::FileNotFound)?
+            .clone();
         log::debug!("found file owner");
 
-        if session.pending_requests.contains_key(&id) {
-            return None;
+        // Legacy owners can only upload whole files, HTTP allows to ignore the range
+        let range = range
+            .filter(|_| file_owner.version() >= PROTOCOL_V2)
+            .map(resolve_range)
+            .transpose()?;
+
+        let (tx, rx) = relay::relay_channel(self.relay_capacity(), range.map(|range| range.len()).unwrap_or(file.size));
+        let receiver = RelayReceiver {
+            connection_id: requester.filter(|connection_id| session.connections.contains_key(connection_id)),
+            tx,
+        };
+
+        // Whole file downloads can share a single upload in broadcast mode
+        let shared = self.relay.broadcast && range.is_none();
+        let joinable = session
+            .pending_requests
+            .iter_mut()
+            .find(|(_, request)| shared && request.file_id == id && request.range.is_none() && request.state != RequestState::Uploading);
+
+        if let Some((request_id, request)) = joinable {
+            request.receivers.push(receiver);
+            log::debug!("joined request: {}", request_id);
+
+            return Ok(FileRequest {
+                file,
+                range,
+                request_id: Some(*request_id),
+                data: rx,
+            });
+        }
+
+        // Late joiners wait for the second pass
+        let state = if shared
+            && session
+                .pending_requests
+                .values()
+                .any(|request| request.file_id == id && request.range.is_none())
+        {
+            RequestState::Queued
+        } else {
+            RequestState::Requested
+        };
+
+        let request_id = Uuid::new_v4();
+        session.pending_requests.insert(
+            request_id,
+            PendingRequest {
+                file_id: id,
+                range,
+                state,
+                receivers: vec![receiver],
+            },
+        );
+        log::debug!("created request: {}", request_id);
+
+        if state == RequestState::Requested {
+            file_owner.send_external(&WsResponse::FileRequested { id, request_id, range });
         }
 
-        let (tx, rx) = mpsc::unbounded_channel();
-        session.pending_requests.insert(id, tx);
-        log::debug!("created request");
+        Ok(FileRequest {
+            file,
+            range,
+            request_id: Some(request_id),
+            data: rx,
+        })
+    }
 
-        file_owner.send_external(&WsResponse::FileRequested { id });
+    /// Uploads the file of the legacy owner, which doesn't know request ids, for one of its whole file requests
+    pub async fn upload_legacy_file<T, I, E>(&self, id: Uuid, seed: String, content_length: usize, data: T) -> Result<usize, TransferError>
+    where
+        T: Stream<Item = Result<I, E>>,
+        I: Buf,
+        E: std::fmt::Display,
+    {
+        let request_id = {
+            let decoded_seed = decode_seed(&seed).map_err(|_| TransferError::RequestNotFound)?;
+            let session = self
+                .sessions
+                .read()
+                .await
+                .get(&decoded_seed)
+                .ok_or(TransferError::RequestNotFound)?
+                .clone();
+
+            let session = session.read().await;
+            session
+                .pending_requests
+                .iter()
+                .find(|(_, request)| request.file_id == id && request.range.is_none() && request.state == RequestState::Requested)
+                .map(|(&request_id, _)| request_id)
+                .ok_or(TransferError::RequestNotFound)?
+        };
 
-        Some((file, rx))
+        self.upload_file(id, request_id, seed, content_length, data).await
     }
 
-    pub async fn upload_file<T, I>(&self, id: Uuid, seed: String, data: T) -> Option<()>
+    pub async fn upload_file<T, I, E>(
+        &self,
+        id: Uuid,
+        request_id: Uuid,
+        seed: String,
+        content_length: usize,
+        data: T,
+    ) -> Result<usize, TransferError>
     where
-        T: Stream<Item = Result<I, warp::Error>>,
+        T: Stream<Item = Result<I, E>>,
         I: Buf,
+        E: std::fmt::Display,
     {
-        let seed = decode_seed(&seed).ok()?;
+        let seed = decode_seed(&seed).map_err(|_| TransferError::RequestNotFound)?;
         log::debug!("decoded seed: {:?}", seed);
 
-        let stream = {
-            let session = self.sessions.read().await.get(&seed)?.clone();
+        let session = self.sessions.read().await.get(&seed).ok_or(TransferError::RequestNotFound)?.clone();
+
+        let (owner_id, expected, expected_hash, mut receivers) = {
             let mut session = session.write().await;
-            session.pending_requests.remove(&id)?
+            let file = session.find_file(&id).ok_or(TransferError::RequestNotFound)?;
+            let (owner_id, size, hash) = (file.connection_id, file.size, file.hash);
+
+            match session.pending_requests.get_mut(&request_id) {
+                Some(request) if request.file_id == id && request.state == RequestState::Requested => {
+                    // Request stays available for the retry with the correct length
+                    let expected = request.range.map(|range| range.len()).unwrap_or(size);
+                    if content_length != expected {
+                        return Err(TransferError::LengthMismatch {
+                            expected,
+                            actual: content_length,
+                        });
+                    }
+
+                    // Only whole files can be verified
+                    let hash = hash.filter(|_| request.range.is_none());
+
+                    request.state = RequestState::Uploading;
+                    (owner_id, expected, hash, std::mem::take(&mut request.receivers))
+                }
+                _ => return Err(TransferError::RequestNotFound),
+            }
         };
 
-        data.for_each(|part| async {
-            match part {
-                Ok(mut part) => {
-                    println!("part: {:?}", part.remaining());
-                    stream
-                        .send(part.to_bytes())
-                        .unwrap_or_else(|e| println!("error: {}", e.to_string()));
+        let mut hasher = expected_hash.as_ref().map(|hash| Hasher::new(hash.algorithm));
+        let mut actual_hash = None;
+        let mut lagged = Vec::new();
+        let mut bytes = 0;
+
+        futures::pin_mut!(data);
+        let result = loop {
+            match data.next().await {
+                Some(Ok(mut part)) => {
+                    // Next part is polled only after the current one is accepted by receivers
+                    let mut part = part.to_bytes();
+                    bytes += part.len();
+                    if bytes > expected {
+                        break Err(TransferError::TooLarge(expected));
+                    }
+
+                    // Last part is held back until the whole file is verified
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&part);
+                    }
+                    if bytes == expected {
+                        actual_hash = hasher.take().map(Hasher::finalize);
+                        if !digest_matches(&expected_hash, &actual_hash) {
+                            break Err(TransferError::HashMismatch);
+                        }
+                    }
+
+                    while !part.is_empty() && !receivers.is_empty() {
+                        let chunk = part.split_to(part.len().min(MAX_RELAY_PART_SIZE));
+                        let (alive, slow) = relay::relay_part(receivers, chunk, self.relay.max_lag()).await;
+                        receivers = alive;
+
+                        // Lagged downloads are aborted right away instead of stalling until the upload ends,
+                        // if the buffer is still full dropping the sender interrupts the stream
+                        for mut receiver in slow {
+                            let _ = receiver.tx.try_send(Err(TransferError::Lagged));
+                            lagged.extend(receiver.connection_id);
+                        }
+                    }
+
+                    if receivers.is_empty() {
+                        break Err(TransferError::ReceiversGone);
+                    }
                 }
-                Err(e) => {
-                    println!("error: {:?}", e);
+                Some(Err(e)) => break Err(TransferError::UploadFailed(e.to_string())),
+                None if bytes != expected => break Err(TransferError::LengthMismatch { expected, actual: bytes }),
+                None => {
+                    // Empty file has no parts, so its digest is checked once the stream ends
+                    if let Some(hasher) = hasher.take() {
+                        actual_hash = Some(hasher.finalize());
+                        if !digest_matches(&expected_hash, &actual_hash) {
+                            break Err(TransferError::HashMismatch);
+                        }
+                    }
+                    break Ok(bytes);
                 }
             }
-        })
-        .await;
+        };
 
-        Some(())
-    }
+        let mut session = session.write().await;
+        session.pending_requests.remove(&request_id);
+        session.touch();
 
-    pub async fn handle_connection(&self, websocket: WebSocket) {
-        let (conn, mut rx) = websocket::init_connection(websocket);
+        let failed = |reason: &TransferError| WsResponse::TransferFailed {
+            id,
+            request_id,
+            reason: reason.to_string(),
+        };
 
-        // add connection to pending
-        let local_mnemonic = self.create_pending_connection(conn.clone()).await;
-        let mut local_session: Option<ArcRwLock<Session>> = None;
+        for connection_id in lagged {
+            session.send_external_to(connection_id, &failed(&TransferError::Lagged));
+        }
 
-        // notify created
-        conn.send_external(&WsResponse::Created {
-            phrase: local_mnemonic.phrase().to_owned(),
-        });
+        match &result {
+            Ok(bytes) => {
+                log::debug!("request {} completed: {} bytes", request_id, bytes);
+
+                let completed = WsResponse::TransferCompleted {
+                    id,
+                    request_id,
+                    bytes: *bytes,
+                };
+                session.send_external_to(owner_id, &completed);
+                for connection_id in receivers.iter().filter_map(|receiver| receiver.connection_id) {
+                    session.send_external_to(connection_id, &completed);
+                }
+            }
+            Err(e) => {
+                log::debug!("request {} failed: {}", request_id, e);
 
-        // handle events
-        while let Some(request) = rx.next().await {
-            match request {
-                Event::External(WsRequest::Connect { phrase }) => {
-                    const MIN_PHRASE_LEN: usize = 6 * 3 + 5; // 6 words with 3 letters + 5 spaces
-                    const MAX_PHRASE_LEN: usize = 6 * 8 + 5; // 6 words with 8 letters + 5 spaces
-                    if phrase.len() < MIN_PHRASE_LEN || phrase.len() > MAX_PHRASE_LEN || phrase == local_mnemonic.phrase() {
-                        conn.send_external(&WsResponse::PeerNotFound);
-                        continue;
+                if let (TransferError::HashMismatch, Some(expected), Some(actual)) = (e, expected_hash, actual_hash) {
+                    let mismatch = WsResponse::HashMismatch {
+                        id,
+                        request_id,
+                        expected,
+                        actual,
+                    };
+                    session.send_external_to(owner_id, &mismatch);
+                    for connection_id in receivers.iter().filter_map(|receiver| receiver.connection_id) {
+                        session.send_external_to(connection_id, &mismatch);
                     }
+                }
 
-                    let peer = match self.remove_pending_peer(&phrase).await {
-                        Some(entry) => entry,
-                        None => {
-                            conn.send_external(&WsResponse::PeerNotFound);
-                            continue;
-                        }
-                    };
+                session.send_external_to(owner_id, &failed(e));
+                for receiver in receivers.iter_mut() {
+                    // Abort the download stream, otherwise it fails on its own because of the missing bytes
+                    let _ = receiver.tx.try_send(Err(e.clone()));
+                    if let Some(connection_id) = receiver.connection_id {
+                        session.send_external_to(connection_id, &failed(e));
+                    }
+                }
+            }
+        }
 
-                    // If session exists
-                    if let Some(session) = local_session.as_ref() {
-                        let (seed, files) = {
-                            let mut session = session.write().await;
-
-                            // Add peer to connections
-                            session.connections.insert(peer.id(), peer.clone());
-                            let seed = session.seed.clone();
-                            let files = session.files.iter().map(|(_, file)| file).cloned().collect::<Vec<_>>();
-                            (seed, files)
-                        };
-                        let seed = encode_seed(&seed);
-
-                        peer.send_internal(InternalMessage::SessionCreated(session.clone()));
-                        peer.send_external(&WsResponse::Connected {
-                            connection_id: peer.id(),
-                            seed,
-                            files,
-                        });
-                    } else {
-                        // Remove host from pending peer
-                        self.remove_pending_peer(local_mnemonic.phrase()).await;
+        session.start_queued_request(id);
 
-                        // Create new session
-                        let mut session = Session::new(&local_mnemonic, &self.seed_password, conn.clone());
-                        let seed = session.seed.clone();
-                        let encoded_seed = encode_seed(&seed);
+        result
+    }
 
-                        // Add peer to connections
-                        session.connections.insert(peer.id(), peer.clone());
+    pub async fn handle_connection(self: Arc<Self>, websocket: WebSocket, codec: NegotiatedCodec, user_agent: Option<String>) {
+        let (ws_tx, ws_rx) = websocket.split();
+        self.handle_socket(ws_tx, ws_rx, codec, user_agent, false).await
+    }
 
-                        let session = Arc::new(RwLock::new(session));
+    /// Opens the connection for the peer which lives in the server, e.g. a bot,
+    /// it sends and receives JSON frames of the websocket protocol and isn't pinged
+    pub fn connect_local(self: Arc<Self>) -> (UnboundedSender<ws::Message>, UnboundedReceiver<ws::Message>) {
+        let (request_tx, request_rx) = futures::channel::mpsc::unbounded();
+        let (response_tx, response_rx) = futures::channel::mpsc::unbounded();
 
-                        // Init local session
-                        local_session = Some(session.clone());
+        let request_rx = request_rx.map(Ok::<_, std::convert::Infallible>);
+        tokio::spawn(self.handle_socket(response_tx, request_rx, NegotiatedCodec::default(), None, true));
 
-                        // Add new session to self sessions
-                        self.sessions.write().await.insert(seed, session.clone());
+        (request_tx, response_rx)
+    }
 
-                        // Send messages
-                        peer.send_internal(InternalMessage::SessionCreated(session.clone()));
+    /// Local peers can't go away silently, so they are not expected to answer pings
+    async fn handle_socket<Tx, Rx, E>(
+        self: Arc<Self>,
+        ws_tx: Tx,
+        ws_rx: Rx,
+        codec: NegotiatedCodec,
+        user_agent: Option<String>,
+        local: bool,
+    ) where
+        Tx: Sink<ws::Message> + Send + Unpin + 'static,
+        Tx::Error: std::fmt::Display,
+        Rx: Stream<Item = Result<ws::Message, E>> + Send + 'static,
+    {
+        let heartbeat = if local { None } else { Some(&self.heartbeat) };
+        let (conn, mut rx) = websocket::init_connection(ws_tx, ws_rx, codec, heartbeat, &self.queue);
 
-                        peer.send_external(&WsResponse::Connected {
-                            connection_id: peer.id(),
-                            seed: encoded_seed.clone(),
-                            files: Default::default(),
-                        });
-                        conn.send_external(&WsResponse::Connected {
-                            connection_id: conn.id(),
-                            seed: encoded_seed,
-                            files: Default::default(),
-                        });
-                    }
-                }
-                Event::External(WsRequest::AddFile { id, name, mime_type, size }) => {
-                    let mut session = match &local_session {
-                        Some(session) => session.write().await,
-                        None => {
-                            conn.send_external(&WsResponse::SessionNotFound);
-                            continue;
-                        }
-                    };
+        let mut handler = ConnectionHandler::new(self, conn, user_agent, local).await;
+        while let Some(event) = rx.next().await {
+            handler.handle_event(event).await;
+            if handler.is_closing() {
+                break;
+            }
+        }
+        handler.close().await
+    }
 
-                    if session.files.len() + 1 >= MAX_FILE_COUNT {
-                        conn.send_external(&WsResponse::FileCountLimitReached);
-                        continue;
-                    }
+    /// Uploads are relayed in parts of limited size, so the number of parts bounds the bytes buffered for a downloader
+    fn relay_capacity(&self) -> usize {
+        (self.relay.buffer_size / MAX_RELAY_PART_SIZE).max(1)
+    }
 
-                    if session.files.contains_key(&id) {
-                        conn.send_external(&WsResponse::FileAlreadyExists);
-                        continue;
-                    }
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            max_file_count: MAX_FILE_COUNT,
+            resumable_transfers: true,
+            session_resume: self.session_settings.resume_grace().as_secs() > 0,
+            store_and_forward: self.storage.is_some(),
+            binary_frames: true,
+            webrtc: self.rtc.enabled,
+            ice_servers: if self.rtc.enabled {
+                self.rtc.ice_servers.clone()
+            } else {
+                Vec::new()
+            },
+        }
+    }
 
-                    let file_info = FileInfo {
-                        id,
-                        name,
-                        mime_type,
-                        size,
-                        connection_id: conn.id(),
-                    };
-                    session.files.insert(file_info.id, file_info.clone());
-                    session.broadcast_external(&WsResponse::FileAdded(file_info));
-                }
-                Event::External(WsRequest::RemoveFile { id }) => {
-                    let mut session = match &local_session {
-                        Some(session) => session.write().await,
-                        None => {
-                            conn.send_external(&WsResponse::SessionNotFound);
-                            continue;
-                        }
-                    };
+    /// Reattaches the connection to the session in which it was suspended
+    async fn resume_session(&self, seed: &[u8], conn: Connection) -> Option<(ArcRwLock<Session>, Arc<Connection>, WsResponse)> {
+        let session = self.sessions.read().await.get(seed)?.clone();
+        let conn = Arc::new(conn);
 
-                    if session.files.remove(&id).is_some() {
-                        session.broadcast_external(&WsResponse::FileRemoved { id });
-                    }
+        let response = {
+            let mut session = session.write().await;
+            if !session.resume_connection(conn.clone()) {
+                return None;
+            }
+            log::debug!("connection {} resumed", conn.id());
+
+            session.connected(conn.id(), self.resume_tokens.issue(seed, conn.id()))
+        };
+
+        Some((session, conn, response))
+    }
+
+    /// Requests the file from its owner and writes it to the storage
+    async fn store_file(&self, id: Uuid, seed: String, session: ArcRwLock<Session>) {
+        let storage = match &self.storage {
+            Some(storage) => storage.clone(),
+            None => return,
+        };
+
+        let (size, data) = match self.request_file(id, seed, None, None).await {
+            Ok(request) => (request.file.size, request.data),
+            Err(e) => {
+                log::warn!("failed to request file {} for storage: {}", id, e);
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            let key = Uuid::new_v4();
+            if let Err(e) = storage.put(key, size, data).await {
+                log::warn!("failed to store file {}: {}", id, e);
+                return;
+            }
+
+            let expires_at = chrono::Utc::now() + chrono::Duration::from_std(storage.ttl()).unwrap_or_else(|_| chrono::Duration::zero());
+
+            let stored = {
+                let mut session = session.write().await;
+                let stored = session.mark_stored(id, StoredFile { key, expires_at });
+                if stored {
+                    session.broadcast_external(&WsResponse::FileStored { id, expires_at });
                 }
-                Event::Internal(InternalMessage::SessionCreated(new_session)) => local_session = Some(new_session),
+                stored
             };
-        }
 
-        self.remove_connection(conn, local_mnemonic, local_session).await
+            // File was removed during the upload
+            if !stored {
+                storage.remove(key).await;
+            }
+        });
     }
 
-    async fn remove_pending_peer<T: AsRef<str>>(&self, phrase: T) -> Option<Arc<Connection>> {
+    async fn remove_pending_peer<T: AsRef<str>>(&self, phrase: T) -> Option<PendingConnection> {
         let mut pending_connections = self.pending_connections.write().await;
         // Remove peer from `pending_connections`
         pending_connections.remove(phrase.as_ref())
     }
 
-    async fn create_pending_connection(&self, conn: Arc<Connection>) -> Mnemonic {
-        let mtype = MnemonicType::Words6;
-        let lang = Language::English;
-
-        // generate mnemonic
-        let mut mnemonic = Mnemonic::new(mtype, lang);
+    async fn update_pending_profile<T: AsRef<str>>(&self, phrase: T, profile: PeerProfile) {
+        if let Some(pending) = self.pending_connections.write().await.get_mut(phrase.as_ref()) {
+            pending.profile = profile;
+        }
+    }
 
+    async fn create_pending_connection(&self, conn: Arc<Connection>, profile: PeerProfile, local: bool) -> Mnemonic {
         let mut pending_connections = self.pending_connections.write().await;
+        let invites = self.invites.read().await;
 
-        // prevent collisions
-        loop {
-            if pending_connections.contains_key(mnemonic.phrase()) {
-                mnemonic = Mnemonic::new(mtype, lang);
-            } else {
-                break;
-            }
-        }
+        let mnemonic = generate_mnemonic(|phrase| pending_connections.contains_key(phrase) || invites.contains_key(phrase));
 
         let phrase = mnemonic.phrase().to_owned();
-        pending_connections.insert(phrase, conn);
+        pending_connections.insert(
+            phrase,
+            PendingConnection {
+                connection: conn,
+                profile,
+                local,
+                created_at: std::time::Instant::now(),
+            },
+        );
 
         mnemonic
     }
@@ -260,22 +669,20 @@ impl SessionService {
         };
 
         let session_seed = {
-            // Remove self from session connections
             let mut session = session.write().await;
-            session.connections.remove(&conn.id());
 
-            // Remove all owned files
-            let conn_files = session
-                .files
-                .iter()
-                .filter_map(|(_, file)| if file.connection_id == conn.id() { Some(file.id) } else { None })
-                .collect::<Vec<_>>();
-            for id in conn_files.into_iter() {
-                session.files.remove(&id);
-                session.broadcast_external_except(conn.id(), &WsResponse::FileRemoved { id });
+            // Connection was already removed from the expired session
+            if !session.connections.contains_key(&conn.id()) {
+                return;
+            }
+
+            if self.session_settings.resume_grace().as_secs() > 0 {
+                session.suspend_connection(conn.id());
+            } else {
+                session.evict_connection(conn.id());
             }
 
-            if session.connections.is_empty() {
+            if session.is_abandoned() {
                 Some(session.seed.clone())
             } else {
                 None
@@ -284,11 +691,108 @@ impl SessionService {
 
         // Remove session from `self.sessions` if session is empty
         if let Some(seed) = session_seed {
-            self.sessions.write().await.remove(&seed);
+            self.remove_session(&seed).await;
+        }
+    }
+
+    /// Removes the session together with its invites
+    async fn remove_session(&self, seed: &[u8]) {
+        self.sessions.write().await.remove(seed);
+        self.invites.write().await.retain(|_, invite| invite.seed != seed);
+    }
+
+    /// Creates the phrase which lets devices join the session, one-time invites have the TTL
+    async fn create_invite(&self, seed: &[u8], ttl: Option<std::time::Duration>) -> Phrase {
+        let pending_connections = self.pending_connections.read().await;
+        let mut invites = self.invites.write().await;
+
+        let mnemonic = generate_mnemonic(|phrase| pending_connections.contains_key(phrase) || invites.contains_key(phrase));
+        let phrase = mnemonic.phrase().to_owned();
+        invites.insert(
+            phrase.clone(),
+            Invite {
+                seed: seed.to_vec(),
+                expires_at: ttl.map(|ttl| std::time::Instant::now() + ttl),
+            },
+        );
+
+        phrase
+    }
+
+    /// Peers which know the phrase can't join with it anymore
+    async fn remove_invite(&self, phrase: &str) {
+        self.invites.write().await.remove(phrase);
+    }
+
+    /// Finds the session of the invite, one-time invites are used up unless the session is locked
+    async fn take_invite(&self, phrase: &str) -> Result<ArcRwLock<Session>, ErrorCode> {
+        let invite = self
+            .invites
+            .read()
+            .await
+            .get(phrase)
+            .filter(|invite| !invite.is_expired())
+            .cloned()
+            .ok_or(ErrorCode::PeerNotFound)?;
+        let session = self
+            .sessions
+            .read()
+            .await
+            .get(&invite.seed)
+            .cloned()
+            .ok_or(ErrorCode::PeerNotFound)?;
+
+        // Locked session doesn't use up one-time invites
+        if session.read().await.locked {
+            return Err(ErrorCode::SessionLocked);
+        }
+
+        // Invite may have been used by the concurrent join
+        if invite.is_one_time() && self.invites.write().await.remove(phrase).is_none() {
+            return Err(ErrorCode::PeerNotFound);
+        }
+        Ok(session)
+    }
+}
+
+pub struct FileRequest {
+    pub file: FileInfo,
+    pub range: Option<ByteRange>,
+    /// Pending request which relays the data, stored files are served without it
+    pub request_id: Option<Uuid>,
+    pub data: RelayRx,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RequestError {
+    #[error("session not found")]
+    SessionNotFound,
+    #[error("file not found")]
+    FileNotFound,
+    #[error("file is temporarily unavailable")]
+    FileUnavailable,
+    #[error("range not satisfiable")]
+    RangeNotSatisfiable { size: usize },
+}
+
+/// Generates the phrase which isn't taken yet
+fn generate_mnemonic(is_taken: impl Fn(&str) -> bool) -> Mnemonic {
+    loop {
+        let mnemonic = Mnemonic::new(MnemonicType::Words6, Language::English);
+        if !is_taken(mnemonic.phrase()) {
+            return mnemonic;
         }
     }
 }
 
+/// Transfers without the declared digest are not verified
+fn digest_matches(expected: &Option<FileHash>, actual: &Option<FileHash>) -> bool {
+    match (expected, actual) {
+        (Some(expected), Some(actual)) => expected.matches(actual),
+        _ => true,
+    }
+}
+
 fn encode_seed(seed: &[u8]) -> String {
     base64::encode_config(seed, base64::Config::new(base64::CharacterSet::UrlSafe, true))
 }
@@ -298,3 +802,61 @@ fn decode_seed(seed: &str) -> Result<Vec<u8>, base64::DecodeError> {
 }
 
 const MAX_FILE_COUNT: usize = 10;
+const MAX_BUNDLE_ENTRY_COUNT: usize = 10_000;
+const MAX_RELAY_PART_SIZE: usize = 64 * 1024;
+const STORAGE_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+const SESSION_REAPER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{self, TestPeer};
+    use super::*;
+
+    #[tokio::test]
+    async fn running_upload_keeps_idle_session() {
+        let session_service = testing::session_service_with(serde_json::json!({ "sessions": { "idle_ttl_secs": 0 } }));
+
+        let mut owner = TestPeer::connect(&session_service);
+        let phrase = owner.recv("created").await["phrase"].as_str().unwrap().to_owned();
+        let mut host = TestPeer::connect(&session_service);
+        host.send(&WsRequest::Connect { phrase });
+        let seed = host.recv("connected").await["seed"].as_str().unwrap().to_owned();
+
+        let id = Uuid::new_v4();
+        owner.send(&WsRequest::AddFile {
+            id,
+            name: "notes.txt".to_owned(),
+            mime_type: "text/plain".to_owned(),
+            size: 5,
+            hash: None,
+            persist: false,
+        });
+        host.recv("file_added").await;
+
+        let session = session_service.sessions.read().await.values().next().unwrap().clone();
+        assert!(session_service.is_session_expired(&*session.read().await));
+
+        let _request = session_service.request_file(id, seed.clone(), None, None).await.unwrap();
+        let request_id = owner.recv("file_requested").await["request_id"]
+            .as_str()
+            .unwrap()
+            .parse::<Uuid>()
+            .unwrap();
+
+        // Upload stalls after the first part, like a slow owner does
+        let data = futures::stream::once(async { Ok::<_, std::convert::Infallible>(bytes::Bytes::from_static(b"he")) })
+            .chain(futures::stream::pending());
+        tokio::spawn({
+            let session_service = session_service.clone();
+            async move { session_service.upload_file(id, request_id, seed, 5, data).await }
+        });
+
+        let uploading = async {
+            while !session.read().await.is_uploading() {
+                tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), uploading).await.unwrap();
+        assert!(!session_service.is_session_expired(&*session.read().await));
+    }
+}
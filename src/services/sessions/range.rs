@@ -0,0 +1,142 @@
+use std::str::FromStr;
+
+use crate::prelude::*;
+
+/// Inclusive byte range of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl ByteRange {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, size: usize) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, size)
+    }
+}
+
+/// Single range from the `Range` header, not yet resolved against the file size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeSpec {
+    /// `bytes=<start>-` or `bytes=<start>-<end>`
+    FromTo(usize, Option<usize>),
+    /// `bytes=-<length>`
+    Suffix(usize),
+}
+
+impl RangeSpec {
+    /// Returns `None` if the range is not satisfiable for the file of the specified size
+    pub fn resolve(&self, size: usize) -> Option<ByteRange> {
+        if size == 0 {
+            return None;
+        }
+
+        match *self {
+            RangeSpec::FromTo(start, _) if start >= size => None,
+            RangeSpec::FromTo(start, end) => Some(ByteRange {
+                start,
+                end: end.map(|end| end.min(size - 1)).unwrap_or(size - 1),
+            }),
+            RangeSpec::Suffix(0) => None,
+            RangeSpec::Suffix(length) => Some(ByteRange {
+                start: size.saturating_sub(length),
+                end: size - 1,
+            }),
+        }
+    }
+}
+
+impl FromStr for RangeSpec {
+    type Err = ();
+
+    /// Only single `bytes` ranges are supported, multipart ranges are rejected
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let range = s.trim().strip_prefix("bytes=").ok_or(())?.trim();
+        if range.contains(',') {
+            return Err(());
+        }
+
+        let (start, end) = match range.find('-') {
+            Some(index) => (range[..index].trim(), range[index + 1..].trim()),
+            None => return Err(()),
+        };
+
+        match (start.is_empty(), end.is_empty()) {
+            (true, false) => end.parse().map(RangeSpec::Suffix).map_err(|_| ()),
+            (false, true) => start.parse().map(|start| RangeSpec::FromTo(start, None)).map_err(|_| ()),
+            (false, false) => {
+                let start = start.parse().map_err(|_| ())?;
+                let end = end.parse().map_err(|_| ())?;
+                if end < start {
+                    return Err(());
+                }
+                Ok(RangeSpec::FromTo(start, Some(end)))
+            }
+            (true, true) => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(header: &str, size: usize) -> Option<ByteRange> {
+        header.parse::<RangeSpec>().ok()?.resolve(size)
+    }
+
+    #[test]
+    fn parses_single_ranges() {
+        assert_eq!("bytes=0-99".parse(), Ok(RangeSpec::FromTo(0, Some(99))));
+        assert_eq!("bytes=100-".parse(), Ok(RangeSpec::FromTo(100, None)));
+        assert_eq!("bytes=-500".parse(), Ok(RangeSpec::Suffix(500)));
+        assert_eq!(" bytes= 5 - 5 ".parse(), Ok(RangeSpec::FromTo(5, Some(5))));
+    }
+
+    #[test]
+    fn rejects_malformed_ranges() {
+        assert_eq!("bytes=0-99,200-299".parse::<RangeSpec>(), Err(()));
+        assert_eq!("bytes=10-5".parse::<RangeSpec>(), Err(()));
+        assert_eq!("bytes=-".parse::<RangeSpec>(), Err(()));
+        assert_eq!("bytes=5".parse::<RangeSpec>(), Err(()));
+        assert_eq!("bytes=a-b".parse::<RangeSpec>(), Err(()));
+        assert_eq!("items=0-1".parse::<RangeSpec>(), Err(()));
+    }
+
+    #[test]
+    fn resolves_against_size() {
+        assert_eq!(resolve("bytes=0-99", 1000), Some(ByteRange { start: 0, end: 99 }));
+        assert_eq!(resolve("bytes=900-", 1000), Some(ByteRange { start: 900, end: 999 }));
+        assert_eq!(resolve("bytes=-100", 1000), Some(ByteRange { start: 900, end: 999 }));
+        // End past the file is clamped to the last byte
+        assert_eq!(resolve("bytes=500-5000", 1000), Some(ByteRange { start: 500, end: 999 }));
+    }
+
+    #[test]
+    fn suffix_longer_than_file_covers_whole_file() {
+        let range = resolve("bytes=-5000", 1000).unwrap();
+        assert_eq!(range, ByteRange { start: 0, end: 999 });
+        assert_eq!(range.len(), 1000);
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(resolve("bytes=1000-", 1000), None);
+        assert_eq!(resolve("bytes=1000-1001", 1000), None);
+        assert_eq!(resolve("bytes=-0", 1000), None);
+        assert_eq!(resolve("bytes=0-", 0), None);
+        assert_eq!(resolve("bytes=-10", 0), None);
+    }
+
+    #[test]
+    fn content_range() {
+        let range = ByteRange { start: 10, end: 19 };
+        assert_eq!(range.len(), 10);
+        assert_eq!(range.content_range(100), "bytes 10-19/100");
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use futures::task::{Context, Poll};
+use futures::Stream;
+
+use super::websocket::ConnectionId;
+use crate::prelude::*;
+
+pub fn relay_channel(capacity: usize, expected_len: usize) -> (RelayTx, RelayRx) {
+    let (tx, rx) = mpsc::channel(capacity);
+    (tx, RelayRx::new(rx, expected_len))
+}
+
+#[derive(Debug)]
+pub struct RelayReceiver {
+    /// Connection which requested the file, if known
+    pub connection_id: Option<ConnectionId>,
+    pub tx: RelayTx,
+}
+
+/// Sends part to all receivers, returns those which are still alive and those which lagged behind
+pub async fn relay_part(receivers: Vec<RelayReceiver>, part: bytes::Bytes, max_lag: Duration) -> (Vec<RelayReceiver>, Vec<RelayReceiver>) {
+    // Single receiver can be as slow as it wants
+    let shared = receivers.len() > 1;
+
+    let sent = receivers.into_iter().map(|mut receiver| {
+        let part = part.clone();
+        async move {
+            let sent = if shared {
+                tokio::time::timeout(max_lag, receiver.tx.send(Ok(part))).await
+            } else {
+                Ok(receiver.tx.send(Ok(part)).await)
+            };
+            (receiver, sent)
+        }
+    });
+
+    let mut alive = Vec::new();
+    let mut lagged = Vec::new();
+    for (receiver, sent) in futures::future::join_all(sent).await {
+        match sent {
+            Ok(Ok(_)) => alive.push(receiver),
+            // Receiver has gone away by itself
+            Ok(Err(_)) => {}
+            Err(_) => lagged.push(receiver),
+        }
+    }
+
+    (alive, lagged)
+}
+
+/// Stream of relayed parts which fails if the transfer ends before all expected bytes were received
+#[derive(Debug)]
+pub struct RelayRx {
+    rx: mpsc::Receiver<RelayItem>,
+    remaining: usize,
+    finished: bool,
+}
+
+impl RelayRx {
+    fn new(rx: mpsc::Receiver<RelayItem>, expected_len: usize) -> Self {
+        Self {
+            rx,
+            remaining: expected_len,
+            finished: false,
+        }
+    }
+}
+
+impl Stream for RelayRx {
+    type Item = RelayItem;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.finished {
+            return Poll::Ready(None);
+        }
+
+        match self.rx.poll_recv(cx) {
+            Poll::Ready(Some(Ok(part))) => {
+                self.remaining = self.remaining.saturating_sub(part.len());
+                Poll::Ready(Some(Ok(part)))
+            }
+            Poll::Ready(Some(Err(e))) => {
+                self.finished = true;
+                Poll::Ready(Some(Err(e)))
+            }
+            Poll::Ready(None) if self.remaining > 0 => {
+                self.finished = true;
+                Poll::Ready(Some(Err(TransferError::Interrupted)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum TransferError {
+    #[error("request not found")]
+    RequestNotFound,
+    #[error("upload failed: {0}")]
+    UploadFailed(String),
+    #[error("length mismatch: expected {expected} bytes, got {actual}")]
+    LengthMismatch { expected: usize, actual: usize },
+    #[error("upload exceeds expected {0} bytes")]
+    TooLarge(usize),
+    #[error("hash mismatch")]
+    HashMismatch,
+    #[error("file {0} is unavailable")]
+    Unavailable(uuid::Uuid),
+    #[error("storage failed: {0}")]
+    StorageFailed(String),
+    #[error("all receivers are gone")]
+    ReceiversGone,
+    #[error("receiver is too slow")]
+    Lagged,
+    #[error("transfer was interrupted")]
+    Interrupted,
+}
+
+pub type RelayItem = Result<bytes::Bytes, TransferError>;
+pub type RelayTx = mpsc::Sender<RelayItem>;
@@ -0,0 +1,67 @@
+use std::convert::TryInto;
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use super::session::Seed;
+use super::websocket::ConnectionId;
+use super::{decode_seed, encode_seed};
+
+/// Issues and verifies tokens which allow a peer to reattach to its session after reconnect
+///
+/// The token is the connection id and the session seed signed with the key generated on startup,
+/// so tokens become invalid after restart together with the sessions.
+pub struct ResumeTokens {
+    key: Vec<u8>,
+}
+
+impl ResumeTokens {
+    pub fn new() -> Self {
+        let key = [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()]
+            .iter()
+            .flat_map(|part| part.as_bytes().to_vec())
+            .collect();
+
+        Self { key }
+    }
+
+    pub fn issue(&self, seed: &[u8], connection_id: ConnectionId) -> String {
+        let mut token = (connection_id as u64).to_be_bytes().to_vec();
+        token.extend_from_slice(seed);
+
+        let signature = self.mac(&token).finalize().into_bytes();
+        token.extend_from_slice(&signature);
+
+        encode_seed(&token)
+    }
+
+    pub fn verify(&self, token: &str) -> Option<(Seed, ConnectionId)> {
+        let token = decode_seed(token).ok()?;
+        if token.len() < CONNECTION_ID_LEN + SIGNATURE_LEN {
+            return None;
+        }
+
+        let (payload, signature) = token.split_at(token.len() - SIGNATURE_LEN);
+        self.mac(payload).verify(signature).ok()?;
+
+        let (connection_id, seed) = payload.split_at(CONNECTION_ID_LEN);
+        let connection_id = u64::from_be_bytes(connection_id.try_into().ok()?) as ConnectionId;
+
+        Some((seed.to_vec(), connection_id))
+    }
+
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("hmac accepts keys of any size");
+        mac.update(payload);
+        mac
+    }
+}
+
+impl Default for ResumeTokens {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+const CONNECTION_ID_LEN: usize = 8;
+const SIGNATURE_LEN: usize = 32;
@@ -1,12 +1,39 @@
+use std::borrow::Cow;
+use std::time::Instant;
+
 use bip39::Mnemonic;
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
-use super::websocket::{self, ConnectionId};
+use super::codec::NegotiatedCodec;
+use super::encode_seed;
+use super::hash::FileHash;
+use super::range::ByteRange;
+use super::relay::RelayReceiver;
+use super::websocket::{self, ConnectionId, ProtocolVersion, SupersedeKey, Superseding, Versioned};
 use crate::prelude::*;
+use crate::settings::IceServer;
 
+/// Request with the optional client-chosen id which is echoed in `Ack` or `Error`
 #[derive(Debug, Clone, Deserialize)]
+pub struct WsRequestEnvelope {
+    #[serde(default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub request: WsRequest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "content", rename_all = "snake_case")]
 pub enum WsRequest {
+    /// Selects the protocol version, answered with `WsResponse::Hello`
+    Hello {
+        version: ProtocolVersion,
+        /// How the peer is shown to others
+        #[serde(default)]
+        profile: Option<PeerProfile>,
+    },
+    UpdateProfile(PeerProfile),
     Connect {
         phrase: String,
     },
@@ -15,61 +42,763 @@ pub enum WsRequest {
         name: String,
         mime_type: String,
         size: usize,
+        hash: Option<FileHash>,
+        /// Upload the file to the server storage right away
+        #[serde(default)]
+        persist: bool,
     },
     RemoveFile {
         id: Uuid,
     },
+    AddBundle {
+        id: Uuid,
+        name: String,
+        entries: Vec<BundleEntry>,
+    },
+    /// Reattach to the session after reconnect
+    Resume {
+        token: String,
+    },
+    /// Creates the one-time phrase which lets another device join the session
+    CreateInvite,
+    /// Answers `WsResponse::FileRequested` with data frames instead of the HTTP upload
+    StartUpload {
+        id: Uuid,
+        request_id: Uuid,
+    },
+    /// Asks the owner of the file to open a direct WebRTC connection,
+    /// `transfer_id` is chosen by the downloader and identifies the negotiation
+    RtcOffer {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        id: Uuid,
+        sdp: String,
+    },
+    RtcAnswer {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        sdp: String,
+    },
+    RtcCandidate {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        candidate: IceCandidate,
+    },
+    /// Gives up the direct connection, the downloader falls back to the HTTP relay
+    RtcClose {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        reason: Option<String>,
+    },
+    /// Removes the peer from the session, it can't resume afterwards. Host only
+    KickPeer {
+        connection_id: ConnectionId,
+    },
+    /// Locked session can't be joined by new peers, suspended ones can still resume. Host only
+    LockSession {
+        locked: bool,
+    },
+    /// Makes another connected peer the host. Host only
+    TransferHost {
+        connection_id: ConnectionId,
+    },
 }
 
 #[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type", content = "content", rename_all = "snake_case")]
 pub enum WsResponse {
+    /// Answer to `WsRequest::Hello` with all supported versions, it's sent with the negotiated version
+    Hello {
+        versions: Vec<ProtocolVersion>,
+        capabilities: Capabilities,
+    },
+    /// Also announces what the server supports, so the client can pick the version before it sends `Hello`
     Created {
         phrase: String,
+        versions: Vec<ProtocolVersion>,
+        capabilities: Capabilities,
     },
     Connected {
         connection_id: usize,
         seed: String,
         files: Vec<FileInfo>,
+        bundles: Vec<BundleInfo>,
+        /// Allows to reattach to the session with `WsRequest::Resume` after reconnect
+        resume_token: String,
+        /// Other devices join the session by connecting with this phrase
+        join_phrase: String,
+        /// Peer which may moderate the session
+        host: ConnectionId,
+        locked: bool,
+    },
+    /// Roster of the session, sent right after `Connected`
+    PeerList {
+        peers: Vec<PeerInfo>,
     },
+    PeerJoined(PeerInfo),
+    /// Profile or presence of the peer has changed
+    PeerUpdated(PeerInfo),
+    PeerLeft {
+        connection_id: ConnectionId,
+    },
+    InviteCreated {
+        phrase: String,
+        expires_at: DateTime<Utc>,
+    },
+    HostChanged {
+        connection_id: ConnectionId,
+    },
+    SessionLocked {
+        locked: bool,
+    },
+    /// Join phrase was replaced after the host kicked a peer, the previous one doesn't work anymore
+    JoinPhraseChanged {
+        join_phrase: String,
+    },
+    /// Peer was removed by the host, the connection is closed right after it
+    Kicked,
     FileAdded(FileInfo),
     FileRemoved {
         id: Uuid,
     },
+    FileStored {
+        id: Uuid,
+        expires_at: DateTime<Utc>,
+    },
+    BundleAdded(BundleInfo),
+    BundleRemoved {
+        id: Uuid,
+    },
+    /// File or bundle owner has disconnected and may come back within the grace window
+    FileUnavailable {
+        id: Uuid,
+    },
+    FileAvailable {
+        id: Uuid,
+    },
     FileRequested {
         id: Uuid,
+        request_id: Uuid,
+        range: Option<ByteRange>,
+    },
+    TransferCompleted {
+        id: Uuid,
+        request_id: Uuid,
+        bytes: usize,
+    },
+    TransferFailed {
+        id: Uuid,
+        request_id: Uuid,
+        reason: String,
+    },
+    HashMismatch {
+        id: Uuid,
+        request_id: Uuid,
+        expected: FileHash,
+        actual: FileHash,
+    },
+    /// Allows the owner to send more data frames of the upload started with `WsRequest::StartUpload`
+    UploadCredits {
+        request_id: Uuid,
+        credits: usize,
     },
+    /// WebRTC signalling relayed from the peer with `connection_id`
+    RtcOffer {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        id: Uuid,
+        sdp: String,
+    },
+    RtcAnswer {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        sdp: String,
+    },
+    RtcCandidate {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        candidate: IceCandidate,
+    },
+    RtcClose {
+        connection_id: ConnectionId,
+        transfer_id: Uuid,
+        reason: Option<String>,
+    },
+    SessionExpired,
+    /// Command was handled successfully
+    Ack {
+        request_id: Option<String>,
+    },
+    /// Command failed or the frame couldn't be parsed
+    Error {
+        request_id: Option<String>,
+        code: ErrorCode,
+        message: String,
+    },
+    // Messages of the protocol v1
+    #[serde(rename = "file_requested")]
+    LegacyFileRequested {
+        id: Uuid,
+    },
+    PeerNotFound,
+    SessionNotFound,
+    FileCountLimitReached,
+    FileAlreadyExists,
+    InvalidFileHash,
+    InvalidBundle,
+    StorageUnavailable,
+    ResumeFailed,
+}
+
+impl Versioned for WsResponse {
+    fn for_version(&self, version: ProtocolVersion) -> Option<Cow<'_, Self>> {
+        if version >= PROTOCOL_V2 {
+            return Some(Cow::Borrowed(self));
+        }
+
+        // Protocol v1 has no acknowledgements and reports errors with separate messages,
+        // messages which were added later aren't sent at all. Negotiation replies are sent as is,
+        // only the clients which know the newer shapes ask for them
+        let legacy = match self {
+            WsResponse::Hello { .. }
+            | WsResponse::Error {
+                code: ErrorCode::UnsupportedVersion,
+                ..
+            }
+            | WsResponse::Created { .. }
+            | WsResponse::Connected { .. }
+            | WsResponse::FileAdded(_)
+            | WsResponse::FileRemoved { .. }
+            | WsResponse::LegacyFileRequested { .. }
+            | WsResponse::PeerNotFound
+            | WsResponse::SessionNotFound
+            | WsResponse::FileCountLimitReached
+            | WsResponse::FileAlreadyExists
+            | WsResponse::InvalidFileHash
+            | WsResponse::InvalidBundle
+            | WsResponse::StorageUnavailable
+            | WsResponse::ResumeFailed => return Some(Cow::Borrowed(self)),
+            // Owner uploads to the legacy route, which picks the request by the file id
+            WsResponse::FileRequested { id, range: None, .. } => WsResponse::LegacyFileRequested { id: *id },
+            WsResponse::Error { code, .. } => match code {
+                ErrorCode::PeerNotFound | ErrorCode::SessionLocked => WsResponse::PeerNotFound,
+                ErrorCode::SessionNotFound => WsResponse::SessionNotFound,
+                ErrorCode::FileCountLimitReached => WsResponse::FileCountLimitReached,
+                ErrorCode::FileAlreadyExists => WsResponse::FileAlreadyExists,
+                ErrorCode::InvalidFileHash => WsResponse::InvalidFileHash,
+                ErrorCode::InvalidBundle => WsResponse::InvalidBundle,
+                ErrorCode::StorageUnavailable => WsResponse::StorageUnavailable,
+                ErrorCode::ResumeFailed => WsResponse::ResumeFailed,
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        Some(Cow::Owned(legacy))
+    }
+}
+
+impl Superseding for WsResponse {
+    fn supersede_key(&self) -> Option<SupersedeKey> {
+        match self {
+            WsResponse::PeerUpdated(peer) => Some(("peer", peer.connection_id as u128)),
+            WsResponse::FileAvailable { id } | WsResponse::FileUnavailable { id } => Some(("file_availability", id.as_u128())),
+            WsResponse::HostChanged { .. } => Some(("host", 0)),
+            WsResponse::SessionLocked { .. } => Some(("locked", 0)),
+            WsResponse::JoinPhraseChanged { .. } => Some(("join_phrase", 0)),
+            _ => None,
+        }
+    }
+}
+
+/// Server limits and optional features announced in `WsResponse::Hello`
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    pub max_file_count: usize,
+    /// Downloads support `Range` requests
+    pub resumable_transfers: bool,
+    /// Peers can reattach with `WsRequest::Resume` after reconnect
+    pub session_resume: bool,
+    /// Files can be uploaded to the server storage with `persist`
+    pub store_and_forward: bool,
+    /// File data can be sent in binary websocket frames
+    pub binary_frames: bool,
+    /// Peers can negotiate direct transfers with `WsRequest::RtcOffer`
+    pub webrtc: bool,
+    pub ice_servers: Vec<IceServer>,
+}
+
+/// Same shape as `RTCIceCandidateInit` of the browser API, so it's passed through as is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidate {
+    pub candidate: String,
+    #[serde(default)]
+    pub sdp_mid: Option<String>,
+    #[serde(default, rename = "sdpMLineIndex")]
+    pub sdp_m_line_index: Option<u16>,
+    #[serde(default)]
+    pub username_fragment: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, thiserror::Error)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    #[error("malformed request")]
+    InvalidRequest,
+    #[error("unknown request type")]
+    UnknownType,
+    #[error("peer not found")]
     PeerNotFound,
+    #[error("session not found")]
     SessionNotFound,
+    #[error("file not found")]
+    FileNotFound,
+    #[error("file count limit reached")]
     FileCountLimitReached,
+    #[error("file already exists")]
     FileAlreadyExists,
+    #[error("invalid file hash")]
+    InvalidFileHash,
+    #[error("invalid bundle")]
+    InvalidBundle,
+    #[error("storage is unavailable")]
+    StorageUnavailable,
+    #[error("session can't be resumed")]
+    ResumeFailed,
+    #[error("protocol version is not supported")]
+    UnsupportedVersion,
+    #[error("upload request not found")]
+    UploadNotFound,
+    #[error("upload credits exceeded")]
+    CreditsExceeded,
+    #[error("direct transfers are unavailable")]
+    RtcUnavailable,
+    #[error("only the host can do this")]
+    NotAllowed,
+    #[error("session is locked")]
+    SessionLocked,
 }
 
+/// Legacy message shapes, used by clients which don't send `WsRequest::Hello`
+pub const PROTOCOL_V1: ProtocolVersion = 1;
+/// Structured errors with request ids and acknowledgements
+pub const PROTOCOL_V2: ProtocolVersion = 2;
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[ProtocolVersion] = &[PROTOCOL_V1, PROTOCOL_V2];
+
+const MAX_PEER_NAME_LEN: usize = 64;
+const MAX_USER_AGENT_LEN: usize = 256;
+
 #[derive(Debug, Clone)]
 pub enum InternalMessage {
     SessionCreated(ArcRwLock<Session>),
+    /// Host has removed the peer from the session, the connection must be closed
+    Kicked,
+    /// Pairing phrase or session has expired, the connection must be closed
+    SessionExpired,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingConnection {
+    pub connection: Arc<Connection>,
+    /// Kept up to date by the handler, so the peer is announced with it once paired
+    pub profile: PeerProfile,
+    /// Peer is served in process, like the Telegram chat, rather than by a device
+    pub local: bool,
+    pub created_at: Instant,
+}
+
+/// What the peer tells others about itself
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerProfile {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub device_type: DeviceType,
+    /// Taken from the websocket request if the peer doesn't announce it
+    #[serde(default)]
+    pub user_agent: Option<String>,
+}
+
+impl PeerProfile {
+    /// Limits the length of the announced values
+    pub fn sanitized(mut self) -> Self {
+        self.name = self.name.trim().chars().take(MAX_PEER_NAME_LEN).collect();
+        self.user_agent = self
+            .user_agent
+            .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LEN).collect());
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    Bot,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    pub connection_id: ConnectionId,
+    #[serde(flatten)]
+    pub profile: PeerProfile,
+    /// Suspended peers stay in the roster until they resume or the grace period ends
+    pub online: bool,
+}
+
+/// Phrase which lets a device join the existing session
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub seed: Seed,
+    /// One-time invites expire, the join phrase of the session lives as long as the session
+    pub expires_at: Option<Instant>,
+}
+
+impl Invite {
+    #[inline]
+    pub fn is_one_time(&self) -> bool {
+        self.expires_at.is_some()
+    }
+
+    #[inline]
+    pub fn is_expired(&self) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= Instant::now())
+    }
 }
 
 #[derive(Debug)]
 pub struct Session {
     pub seed: Seed,
+    /// Persistent phrase of the session, assigned by the service when the session is created
+    pub join_phrase: Phrase,
     pub connections: HashMap<ConnectionId, Arc<Connection>>,
+    /// Profiles of connected and suspended peers
+    pub profiles: HashMap<ConnectionId, PeerProfile>,
     pub files: HashMap<Uuid, FileInfo>,
-    pub pending_requests: HashMap<Uuid, mpsc::UnboundedSender<bytes::Bytes>>,
+    pub bundles: HashMap<Uuid, BundleInfo>,
+    /// Creator of the session, passed to another peer once it leaves
+    pub host: ConnectionId,
+    /// New peers can't join the locked session
+    pub locked: bool,
+    pub stored_files: HashMap<Uuid, StoredFile>,
+    pub pending_requests: HashMap<Uuid, PendingRequest>,
+    /// Disconnected peers which can resume, with the time of disconnect
+    pub suspended: HashMap<ConnectionId, Instant>,
+    pub created_at: Instant,
+    /// Last time files were added, removed or transferred
+    pub last_activity: Instant,
 }
 
 impl Session {
-    pub fn new<T: AsRef<str>>(mnemonic: &Mnemonic, password: T, host: Arc<Connection>) -> Self {
+    pub fn new<T: AsRef<str>>(mnemonic: &Mnemonic, password: T, host: Arc<Connection>, host_profile: PeerProfile) -> Self {
         let seed = bip39::Seed::new(mnemonic, password.as_ref());
+        let host_id = host.id();
+
+        let mut profiles = HashMap::new();
+        profiles.insert(host.id(), host_profile);
 
         let mut connections = HashMap::new();
         connections.insert(host.id(), host);
 
         Self {
             seed: seed.into_bytes(),
+            join_phrase: Default::default(),
             connections,
+            profiles,
             files: Default::default(),
+            bundles: Default::default(),
+            host: host_id,
+            locked: false,
+            stored_files: Default::default(),
             pending_requests: Default::default(),
+            suspended: Default::default(),
+            created_at: Instant::now(),
+            last_activity: Instant::now(),
+        }
+    }
+
+    #[inline]
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Adds the connection and announces it to the other peers
+    pub fn join(&mut self, connection: Arc<Connection>, profile: PeerProfile) {
+        let connection_id = connection.id();
+        self.profiles.insert(connection_id, profile);
+        self.connections.insert(connection_id, connection);
+        if let Some(peer) = self.peer_info(connection_id) {
+            self.broadcast_external_except(connection_id, &WsResponse::PeerJoined(peer));
+        }
+    }
+
+    /// Replaces the profile of the peer and announces it
+    pub fn update_profile(&mut self, connection_id: ConnectionId, profile: PeerProfile) {
+        self.profiles.insert(connection_id, profile);
+        self.broadcast_peer_updated(connection_id);
+    }
+
+    pub fn peer_info(&self, connection_id: ConnectionId) -> Option<PeerInfo> {
+        let profile = self.profiles.get(&connection_id)?;
+        Some(PeerInfo {
+            connection_id,
+            profile: profile.clone(),
+            online: self.connections.contains_key(&connection_id),
+        })
+    }
+
+    pub fn peer_list(&self) -> WsResponse {
+        let mut peers = self
+            .profiles
+            .keys()
+            .filter_map(|&connection_id| self.peer_info(connection_id))
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.connection_id);
+
+        WsResponse::PeerList { peers }
+    }
+
+    fn broadcast_peer_updated(&self, connection_id: ConnectionId) {
+        if let Some(peer) = self.peer_info(connection_id) {
+            self.broadcast_external(&WsResponse::PeerUpdated(peer));
+        }
+    }
+
+    /// State of the session for the peer which has just joined or resumed
+    pub fn connected(&self, connection_id: ConnectionId, resume_token: String) -> WsResponse {
+        WsResponse::Connected {
+            connection_id,
+            seed: encode_seed(&self.seed),
+            files: self.files.values().cloned().collect(),
+            bundles: self.bundles.values().cloned().collect(),
+            resume_token,
+            join_phrase: self.join_phrase.clone(),
+            host: self.host,
+            locked: self.locked,
+        }
+    }
+
+    #[inline]
+    pub fn is_host(&self, connection_id: ConnectionId) -> bool {
+        self.host == connection_id
+    }
+
+    pub fn set_host(&mut self, connection_id: ConnectionId) {
+        self.host = connection_id;
+        self.broadcast_external(&WsResponse::HostChanged { connection_id });
+    }
+
+    pub fn set_locked(&mut self, locked: bool) {
+        self.locked = locked;
+        self.broadcast_external(&WsResponse::SessionLocked { locked });
+    }
+
+    pub fn set_join_phrase(&mut self, join_phrase: Phrase) {
+        self.join_phrase = join_phrase.clone();
+        self.broadcast_external(&WsResponse::JoinPhraseChanged { join_phrase });
+    }
+
+    /// Searches for the file or the bundle entry with the specified id
+    pub fn find_file(&self, id: &Uuid) -> Option<FileInfo> {
+        if let Some(file) = self.files.get(id) {
+            return Some(file.clone());
+        }
+
+        self.bundles.values().find_map(|bundle| {
+            bundle.entries.iter().find(|entry| entry.id == *id).map(|entry| FileInfo {
+                id: entry.id,
+                name: entry.path.clone(),
+                mime_type: "application/octet-stream".to_owned(),
+                size: entry.size,
+                hash: None,
+                expires_at: None,
+                available: bundle.available,
+                connection_id: bundle.connection_id,
+            })
+        })
+    }
+
+    /// Checks whether the id is used by any file, bundle or bundle entry
+    pub fn contains_id(&self, id: &Uuid) -> bool {
+        self.files.contains_key(id) || self.bundles.contains_key(id) || self.find_file(id).is_some()
+    }
+
+    /// Marks the uploaded file as stored, returns `false` if the file was removed meanwhile
+    pub fn mark_stored(&mut self, id: Uuid, stored_file: StoredFile) -> bool {
+        let file = match self.files.get_mut(&id) {
+            Some(file) => file,
+            None => return false,
+        };
+
+        file.expires_at = Some(stored_file.expires_at);
+        self.stored_files.insert(id, stored_file);
+        true
+    }
+
+    /// Removes the bundle and cancels pending requests of its entries
+    pub fn remove_bundle(&mut self, id: &Uuid) -> Option<BundleInfo> {
+        let bundle = self.bundles.remove(id)?;
+        self.pending_requests
+            .retain(|_, request| !bundle.entries.iter().any(|entry| entry.id == request.file_id));
+        Some(bundle)
+    }
+
+    /// Session without connections, suspended peers and stored files can be removed
+    pub fn is_abandoned(&self) -> bool {
+        self.connections.is_empty() && self.suspended.is_empty() && self.stored_files.is_empty()
+    }
+
+    /// Keeps files and bundles of the disconnected peer, but marks them unavailable until it resumes
+    pub fn suspend_connection(&mut self, connection_id: ConnectionId) {
+        self.connections.remove(&connection_id);
+        self.suspended.insert(connection_id, Instant::now());
+
+        let owned = self.set_available(connection_id, false);
+        self.broadcast_peer_updated(connection_id);
+
+        // Owner may miss the requests, uploads which are already running can still complete
+        self.pending_requests
+            .retain(|_, request| request.state == RequestState::Uploading || !owned.contains(&request.file_id));
+    }
+
+    /// Reattaches the suspended peer, returns `false` if it isn't suspended
+    pub fn resume_connection(&mut self, connection: Arc<Connection>) -> bool {
+        let connection_id = connection.id();
+        if self.suspended.remove(&connection_id).is_none() {
+            return false;
+        }
+
+        self.set_available(connection_id, true);
+        self.connections.insert(connection_id, connection);
+        self.broadcast_peer_updated(connection_id);
+        true
+    }
+
+    /// Removes the peer with its files except stored ones and bundles
+    pub fn evict_connection(&mut self, connection_id: ConnectionId) {
+        self.connections.remove(&connection_id);
+        self.suspended.remove(&connection_id);
+        self.profiles.remove(&connection_id);
+        self.broadcast_external(&WsResponse::PeerLeft { connection_id });
+
+        // Connected peers are preferred, the oldest one becomes the host
+        if self.is_host(connection_id) {
+            let next_host = self.connections.keys().min().or_else(|| self.suspended.keys().min()).copied();
+            if let Some(next_host) = next_host {
+                self.set_host(next_host);
+            }
+        }
+
+        let files = self
+            .files
+            .values()
+            .filter(|file| file.connection_id == connection_id && !self.stored_files.contains_key(&file.id))
+            .map(|file| file.id)
+            .collect::<Vec<_>>();
+        for id in files {
+            self.files.remove(&id);
+            self.pending_requests.retain(|_, request| request.file_id != id);
+            self.broadcast_external(&WsResponse::FileRemoved { id });
+        }
+
+        let bundles = self
+            .bundles
+            .values()
+            .filter(|bundle| bundle.connection_id == connection_id)
+            .map(|bundle| bundle.id)
+            .collect::<Vec<_>>();
+        for id in bundles {
+            self.remove_bundle(&id);
+            self.broadcast_external(&WsResponse::BundleRemoved { id });
+        }
+    }
+
+    /// Updates availability of files and bundles relayed from the peer, returns ids of affected files and entries
+    fn set_available(&mut self, connection_id: ConnectionId, available: bool) -> std::collections::HashSet<Uuid> {
+        let mut owned = std::collections::HashSet::new();
+        let mut changed = Vec::new();
+
+        for file in self.files.values_mut() {
+            if file.connection_id == connection_id && !self.stored_files.contains_key(&file.id) {
+                file.available = available;
+                owned.insert(file.id);
+                changed.push(file.id);
+            }
+        }
+
+        for bundle in self.bundles.values_mut() {
+            if bundle.connection_id == connection_id {
+                bundle.available = available;
+                owned.extend(bundle.entries.iter().map(|entry| entry.id));
+                changed.push(bundle.id);
+            }
+        }
+
+        for id in changed {
+            let message = if available {
+                WsResponse::FileAvailable { id }
+            } else {
+                WsResponse::FileUnavailable { id }
+            };
+            self.broadcast_external_except(connection_id, &message);
+        }
+
+        owned
+    }
+
+    /// Number of files and bundles shared in the session
+    pub fn is_uploading(&self) -> bool {
+        self.pending_requests
+            .values()
+            .any(|request| request.state == RequestState::Uploading)
+    }
+
+    pub fn item_count(&self) -> usize {
+        self.files.len() + self.bundles.len()
+    }
+
+    /// Cancels the request which wasn't answered in time, unless it is already uploading or shared with other receivers
+    pub fn cancel_request(&mut self, request_id: &Uuid) {
+        let file_id = match self.pending_requests.get(request_id) {
+            Some(request) if request.state != RequestState::Uploading && request.receivers.len() <= 1 => request.file_id,
+            _ => return,
+        };
+
+        self.pending_requests.remove(request_id);
+        self.start_queued_request(file_id);
+    }
+
+    /// Notifies the file owner about the request which waits for the second pass
+    pub fn start_queued_request(&mut self, file_id: Uuid) {
+        let owner = match self.find_file(&file_id).and_then(|file| self.connections.get(&file.connection_id).cloned()) {
+            Some(owner) => owner,
+            None => return,
+        };
+
+        let queued = self
+            .pending_requests
+            .iter_mut()
+            .find(|(_, request)| request.file_id == file_id && request.state == RequestState::Queued);
+
+        if let Some((&request_id, request)) = queued {
+            request.state = RequestState::Requested;
+            owner.send_external(&WsResponse::FileRequested {
+                id: file_id,
+                request_id,
+                range: request.range,
+            });
+        }
+    }
+
+    pub fn send_external_to(&self, connection_id: ConnectionId, message: &WsResponse) {
+        if let Some(peer) = self.connections.get(&connection_id) {
+            peer.send_external(message);
         }
     }
 
@@ -91,9 +820,187 @@ pub struct FileInfo {
     pub name: String,
     pub mime_type: String,
     pub size: usize,
+    pub hash: Option<FileHash>,
+    /// Stored files are available until this time even if the owner is offline
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Owner is temporarily disconnected if `false`
+    pub available: bool,
+    pub connection_id: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleInfo {
+    pub id: Uuid,
+    pub name: String,
+    pub entries: Vec<BundleEntry>,
+    /// Owner is temporarily disconnected if `false`
+    pub available: bool,
     pub connection_id: usize,
 }
 
-pub type Connection = websocket::Connection<InternalMessage, WsResponse>;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleEntry {
+    pub id: Uuid,
+    /// Relative path inside the bundle, separated with `/`
+    pub path: String,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    /// Blob key in the storage
+    pub key: Uuid,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug)]
+pub struct PendingRequest {
+    pub file_id: Uuid,
+    pub range: Option<ByteRange>,
+    pub state: RequestState,
+    pub receivers: Vec<RelayReceiver>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestState {
+    /// Waiting for the running upload of the same file to finish
+    Queued,
+    /// Owner was notified, waiting for the upload
+    Requested,
+    /// Upload is in progress, no more receivers can join
+    Uploading,
+}
+
+pub type Connection = websocket::Connection<InternalMessage, WsResponse, NegotiatedCodec>;
 pub type Phrase = String;
 pub type Seed = Vec<u8>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capabilities() -> Capabilities {
+        Capabilities {
+            max_file_count: 1,
+            resumable_transfers: true,
+            session_resume: true,
+            store_and_forward: false,
+            binary_frames: true,
+            webrtc: false,
+            ice_servers: Vec::new(),
+        }
+    }
+
+    fn v1_json(message: &WsResponse) -> Option<serde_json::Value> {
+        message
+            .for_version(PROTOCOL_V1)
+            .map(|message| serde_json::to_value(message.as_ref()).unwrap())
+    }
+
+    #[test]
+    fn v1_file_requested_has_only_the_id() {
+        let id = Uuid::new_v4();
+        let requested = WsResponse::FileRequested {
+            id,
+            request_id: Uuid::new_v4(),
+            range: None,
+        };
+
+        assert_eq!(
+            v1_json(&requested),
+            Some(serde_json::json!({ "type": "file_requested", "content": { "id": id } }))
+        );
+
+        let ranged = WsResponse::FileRequested {
+            id,
+            request_id: Uuid::new_v4(),
+            range: Some(ByteRange { start: 0, end: 1 }),
+        };
+        assert_eq!(v1_json(&ranged), None);
+    }
+
+    #[test]
+    fn v1_errors_use_separate_messages() {
+        let error = |code| WsResponse::Error {
+            request_id: Some("1".to_owned()),
+            code,
+            message: String::new(),
+        };
+
+        assert_eq!(
+            v1_json(&error(ErrorCode::SessionLocked)),
+            Some(serde_json::json!({ "type": "peer_not_found" }))
+        );
+        assert_eq!(
+            v1_json(&error(ErrorCode::FileAlreadyExists)),
+            Some(serde_json::json!({ "type": "file_already_exists" }))
+        );
+        assert_eq!(v1_json(&error(ErrorCode::InvalidRequest)), None);
+    }
+
+    #[test]
+    fn negotiation_replies_are_sent_to_v1() {
+        let hello = WsResponse::Hello {
+            versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            capabilities: capabilities(),
+        };
+        assert_eq!(v1_json(&hello), Some(serde_json::to_value(&hello).unwrap()));
+
+        let unsupported = WsResponse::Error {
+            request_id: Some("1".to_owned()),
+            code: ErrorCode::UnsupportedVersion,
+            message: ErrorCode::UnsupportedVersion.to_string(),
+        };
+        assert_eq!(v1_json(&unsupported), Some(serde_json::to_value(&unsupported).unwrap()));
+
+        let created = WsResponse::Created {
+            phrase: "phrase".to_owned(),
+            versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            capabilities: capabilities(),
+        };
+        let created = v1_json(&created).unwrap();
+        assert_eq!(created["content"]["phrase"], "phrase");
+        assert_eq!(created["content"]["versions"], serde_json::json!(SUPPORTED_PROTOCOL_VERSIONS));
+    }
+
+    #[test]
+    fn v2_only_messages_are_not_sent_to_v1() {
+        let id = Uuid::new_v4();
+        let request_id = Uuid::new_v4();
+        let v2_only = vec![
+            WsResponse::Ack { request_id: None },
+            WsResponse::Kicked,
+            WsResponse::SessionExpired,
+            WsResponse::FileAvailable { id },
+            WsResponse::FileUnavailable { id },
+            WsResponse::FileStored {
+                id,
+                expires_at: Utc::now(),
+            },
+            WsResponse::BundleRemoved { id },
+            WsResponse::TransferCompleted { id, request_id, bytes: 1 },
+            WsResponse::TransferFailed {
+                id,
+                request_id,
+                reason: String::new(),
+            },
+            WsResponse::UploadCredits { request_id, credits: 1 },
+            WsResponse::InviteCreated {
+                phrase: String::new(),
+                expires_at: Utc::now(),
+            },
+            WsResponse::RtcClose {
+                connection_id: 0,
+                transfer_id: id,
+                reason: None,
+            },
+        ];
+
+        for message in &v2_only {
+            assert!(message.for_version(PROTOCOL_V1).is_none(), "{:?}", message);
+            assert!(message.for_version(PROTOCOL_V2).is_some(), "{:?}", message);
+        }
+
+        assert!(v1_json(&WsResponse::FileRemoved { id }).is_some());
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::{BlobStore, BlobStream};
+use crate::prelude::*;
+use crate::services::sessions::range::ByteRange;
+use crate::services::sessions::relay::RelayRx;
+use crate::services::sessions::MAX_RELAY_PART_SIZE;
+
+/// Stores blobs as files in a local directory
+#[derive(Debug)]
+pub struct FsBlobStore {
+    directory: PathBuf,
+}
+
+impl FsBlobStore {
+    pub fn new(directory: &Path) -> Result<Self> {
+        std::fs::create_dir_all(directory)?;
+
+        Ok(Self {
+            directory: directory.to_owned(),
+        })
+    }
+
+    fn path(&self, key: Uuid) -> PathBuf {
+        self.directory.join(key.to_simple().to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl BlobStore for FsBlobStore {
+    async fn put(&self, key: Uuid, _size: usize, mut data: RelayRx) -> Result<()> {
+        let path = self.path(key);
+        let temp_path = path.with_extension("part");
+
+        let result: Result<()> = async {
+            let mut file = tokio::fs::File::create(&temp_path).await?;
+            while let Some(part) = data.next().await {
+                file.write_all(&part?).await?;
+            }
+            file.flush().await?;
+            Ok(())
+        }
+        .await;
+
+        if let Err(e) = result {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+
+        tokio::fs::rename(&temp_path, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: Uuid, range: Option<ByteRange>) -> Result<BlobStream> {
+        let mut file = tokio::fs::File::open(self.path(key)).await?;
+
+        let remaining = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start as u64)).await?;
+                range.len()
+            }
+            None => file.metadata().await?.len() as usize,
+        };
+
+        let parts = futures::stream::unfold((file, remaining), |(mut file, remaining)| async move {
+            if remaining == 0 {
+                return None;
+            }
+
+            let mut buffer = vec![0; remaining.min(MAX_RELAY_PART_SIZE)];
+            match file.read(&mut buffer).await {
+                Ok(0) => Some((Err(anyhow::anyhow!("unexpected end of blob")), (file, 0))),
+                Ok(read) => {
+                    buffer.truncate(read);
+                    Some((Ok(bytes::Bytes::from(buffer)), (file, remaining - read)))
+                }
+                Err(e) => Some((Err(e.into()), (file, 0))),
+            }
+        });
+
+        Ok(parts.boxed())
+    }
+
+    async fn remove(&self, key: Uuid) -> Result<()> {
+        tokio::fs::remove_file(self.path(key)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use uuid::Uuid;
+
+use super::{BlobStore, BlobStream};
+use crate::prelude::*;
+use crate::services::sessions::range::ByteRange;
+use crate::services::sessions::relay::RelayRx;
+use crate::services::sessions::MAX_RELAY_PART_SIZE;
+
+/// Keeps blobs in memory, intended for tests and local development
+#[derive(Debug, Default)]
+pub struct MemoryBlobStore {
+    blobs: RwLock<HashMap<Uuid, Bytes>>,
+}
+
+#[async_trait::async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn put(&self, key: Uuid, size: usize, mut data: RelayRx) -> Result<()> {
+        let mut blob = BytesMut::with_capacity(size);
+        while let Some(part) = data.next().await {
+            blob.extend_from_slice(&part?);
+        }
+
+        self.blobs.write().await.insert(key, blob.freeze());
+        Ok(())
+    }
+
+    async fn get(&self, key: Uuid, range: Option<ByteRange>) -> Result<BlobStream> {
+        let mut blob = self
+            .blobs
+            .read()
+            .await
+            .get(&key)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("blob not found"))?;
+
+        if let Some(range) = range {
+            anyhow::ensure!(range.end < blob.len(), "range exceeds blob size");
+            blob = blob.slice(range.start..=range.end);
+        }
+
+        let parts = futures::stream::unfold(blob, |mut blob| async move {
+            if blob.is_empty() {
+                return None;
+            }
+            let part = blob.split_to(blob.len().min(MAX_RELAY_PART_SIZE));
+            Some((Ok(part), blob))
+        });
+
+        Ok(parts.boxed())
+    }
+
+    async fn remove(&self, key: Uuid) -> Result<()> {
+        self.blobs.write().await.remove(&key);
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+mod fs;
+mod memory;
+mod s3;
+
+use std::time::Duration;
+
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use uuid::Uuid;
+
+pub use self::fs::FsBlobStore;
+pub use self::memory::MemoryBlobStore;
+pub use self::s3::S3BlobStore;
+use super::range::ByteRange;
+use super::relay::{self, RelayRx, TransferError};
+use crate::prelude::*;
+use crate::settings::{StorageBackend, StorageSettings};
+
+pub type BlobStream = BoxStream<'static, Result<bytes::Bytes>>;
+
+/// Backend which keeps files uploaded in store-and-forward mode
+#[async_trait::async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Writes all relayed bytes, the blob becomes available only after the transfer is completed
+    async fn put(&self, key: Uuid, size: usize, data: RelayRx) -> Result<()>;
+
+    /// Streams the whole blob or its range
+    async fn get(&self, key: Uuid, range: Option<ByteRange>) -> Result<BlobStream>;
+
+    async fn remove(&self, key: Uuid) -> Result<()>;
+}
+
+/// Blob store with the configured TTL of stored files
+pub struct Storage {
+    store: Arc<dyn BlobStore>,
+    ttl: Duration,
+}
+
+impl Storage {
+    pub fn new(settings: &StorageSettings) -> Result<Self> {
+        let store: Arc<dyn BlobStore> = match settings.backend {
+            StorageBackend::Fs => Arc::new(FsBlobStore::new(&settings.directory)?),
+            StorageBackend::Memory => Arc::new(MemoryBlobStore::default()),
+            StorageBackend::S3 => {
+                let s3 = settings
+                    .s3
+                    .as_ref()
+                    .ok_or_else(|| anyhow::anyhow!("s3 settings are required for the s3 storage backend"))?;
+                Arc::new(S3BlobStore::new(s3)?)
+            }
+        };
+
+        Ok(Self {
+            store,
+            ttl: settings.ttl(),
+        })
+    }
+
+    #[inline]
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub async fn put(&self, key: Uuid, size: usize, data: RelayRx) -> Result<()> {
+        self.store.put(key, size, data).await
+    }
+
+    /// Streams the whole blob of the specified size or its range
+    pub fn get(&self, key: Uuid, size: usize, range: Option<ByteRange>, capacity: usize) -> RelayRx {
+        let len = range.map(|range| range.len()).unwrap_or(size);
+        let (mut tx, rx) = relay::relay_channel(capacity, len);
+
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            let result: Result<()> = async {
+                let mut parts = store.get(key, range).await?;
+                while let Some(part) = parts.next().await {
+                    if tx.send(Ok(part?)).await.is_err() {
+                        // Downloader has gone away
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            .await;
+
+            if let Err(e) = result {
+                log::warn!("failed to read blob {}: {}", key, e);
+                let _ = tx.send(Err(TransferError::StorageFailed(e.to_string()))).await;
+            }
+        });
+
+        rx
+    }
+
+    pub async fn remove(&self, key: Uuid) {
+        if let Err(e) = self.store.remove(key).await {
+            log::warn!("failed to remove blob {}: {}", key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Method, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::services::sessions::MAX_RELAY_PART_SIZE;
+    use crate::settings::S3Settings;
+
+    async fn put(store: &dyn BlobStore, key: Uuid, data: &[u8]) -> Result<()> {
+        let (mut tx, rx) = relay::relay_channel(4, data.len());
+        let parts = data.chunks(1000).map(bytes::Bytes::copy_from_slice).collect::<Vec<_>>();
+        tokio::spawn(async move {
+            for part in parts {
+                let _ = tx.send(Ok(part)).await;
+            }
+        });
+        store.put(key, data.len(), rx).await
+    }
+
+    async fn get(store: &dyn BlobStore, key: Uuid, range: Option<ByteRange>) -> Result<Vec<u8>> {
+        let mut parts = store.get(key, range).await?;
+        let mut data = Vec::new();
+        while let Some(part) = parts.next().await {
+            data.extend_from_slice(&part?);
+        }
+        Ok(data)
+    }
+
+    async fn check_round_trip(store: &dyn BlobStore) {
+        let key = Uuid::new_v4();
+        let data = (0..MAX_RELAY_PART_SIZE * 2 + 10).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        put(store, key, &data).await.unwrap();
+
+        assert_eq!(get(store, key, None).await.unwrap(), data);
+
+        let across_parts = ByteRange {
+            start: 5,
+            end: MAX_RELAY_PART_SIZE + 5,
+        };
+        assert_eq!(
+            get(store, key, Some(across_parts)).await.unwrap(),
+            &data[5..=MAX_RELAY_PART_SIZE + 5]
+        );
+
+        let last = data.len() - 1;
+        let last_byte = ByteRange { start: last, end: last };
+        assert_eq!(get(store, key, Some(last_byte)).await.unwrap(), &data[last..]);
+
+        let empty = Uuid::new_v4();
+        put(store, empty, &[]).await.unwrap();
+        assert!(get(store, empty, None).await.unwrap().is_empty());
+
+        store.remove(key).await.unwrap();
+        assert!(get(store, key, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_round_trip() {
+        check_round_trip(&MemoryBlobStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn fs_round_trip() {
+        let directory = std::env::temp_dir().join(format!("fbox-test-{}", Uuid::new_v4().to_simple()));
+        check_round_trip(&FsBlobStore::new(&directory).unwrap()).await;
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn fs_keeps_no_partial_blobs() {
+        let directory = std::env::temp_dir().join(format!("fbox-test-{}", Uuid::new_v4().to_simple()));
+        let store = FsBlobStore::new(&directory).unwrap();
+
+        // Transfer ends before all expected bytes were relayed
+        let (mut tx, rx) = relay::relay_channel(1, 10);
+        tx.send(Ok(bytes::Bytes::from_static(b"abc"))).await.unwrap();
+        drop(tx);
+
+        let key = Uuid::new_v4();
+        assert!(store.put(key, 10, rx).await.is_err());
+        assert!(store.get(key, None).await.is_err());
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    /// Minimal path-style S3 API which only checks that requests are signed
+    async fn fake_s3(objects: Arc<std::sync::Mutex<HashMap<String, bytes::Bytes>>>, request: Request<Body>) -> Response<Body> {
+        let status = |status| Response::builder().status(status).body(Body::empty()).unwrap();
+
+        let signed = request
+            .headers()
+            .get(http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("AWS4-HMAC-SHA256 Credential=access/"));
+        if !signed || !request.headers().contains_key("x-amz-date") {
+            return status(StatusCode::FORBIDDEN);
+        }
+
+        let path = request.uri().path().to_owned();
+        match *request.method() {
+            Method::PUT => {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                objects.lock().unwrap().insert(path, body);
+                status(StatusCode::OK)
+            }
+            Method::GET => {
+                let object = match objects.lock().unwrap().get(&path) {
+                    Some(object) => object.clone(),
+                    None => return status(StatusCode::NOT_FOUND),
+                };
+
+                let range = request
+                    .headers()
+                    .get(http::header::RANGE)
+                    .and_then(|value| value.to_str().ok()?.strip_prefix("bytes=")?.split_once('-'))
+                    .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+
+                match range {
+                    Some((start, end)) => Response::builder()
+                        .status(StatusCode::PARTIAL_CONTENT)
+                        .body(Body::from(object.slice(start..=end)))
+                        .unwrap(),
+                    None => Response::new(Body::from(object)),
+                }
+            }
+            Method::DELETE => {
+                objects.lock().unwrap().remove(&path);
+                status(StatusCode::NO_CONTENT)
+            }
+            _ => status(StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    #[tokio::test]
+    async fn s3_round_trip() {
+        let objects = Arc::new(std::sync::Mutex::new(HashMap::new()));
+
+        let make_service = make_service_fn({
+            let objects = objects.clone();
+            move |_| {
+                let objects = objects.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        let objects = objects.clone();
+                        async move { Ok::<_, Infallible>(fake_s3(objects, request).await) }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let endpoint = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+
+        let store = S3BlobStore::new(&S3Settings {
+            endpoint,
+            bucket: "fbox".to_owned(),
+            region: "us-east-1".to_owned(),
+            access_key: "access".to_owned(),
+            secret_key: "secret".to_owned(),
+        })
+        .unwrap();
+
+        check_round_trip(&store).await;
+        assert!(objects.lock().unwrap().keys().all(|path| path.starts_with("/fbox/")));
+    }
+}
@@ -0,0 +1,131 @@
+//! Helpers for tests which drive the service through in-process peers
+
+use std::time::Duration;
+
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::StreamExt;
+use warp::ws;
+
+use super::relay::{self, RelayRx};
+use super::session::{WsRequest, PROTOCOL_V2};
+use super::{NegotiatedCodec, SessionService};
+use crate::prelude::*;
+
+/// Service with the default settings and without the storage
+pub fn session_service() -> Arc<SessionService> {
+    session_service_with(serde_json::json!({}))
+}
+
+/// Service with the settings which override the defaults
+pub fn session_service_with(overrides: serde_json::Value) -> Arc<SessionService> {
+    let mut settings = serde_json::json!({
+        "password": "test",
+        "server_addr": "127.0.0.1:0",
+    });
+    if let (Some(settings), Some(overrides)) = (settings.as_object_mut(), overrides.as_object()) {
+        settings.extend(overrides.clone());
+    }
+
+    SessionService::new(&serde_json::from_value::<Settings>(settings).unwrap()).unwrap()
+}
+
+/// Peer which speaks JSON over the in-process socket like the browser does
+pub struct TestPeer {
+    tx: UnboundedSender<ws::Message>,
+    rx: UnboundedReceiver<ws::Message>,
+}
+
+impl TestPeer {
+    /// Connects the peer which negotiates the current protocol version, requests are handled in order,
+    /// so the following ones are already handled with it
+    pub fn connect(session_service: &Arc<SessionService>) -> Self {
+        let peer = Self::connect_legacy(session_service);
+        peer.send(&WsRequest::Hello {
+            version: PROTOCOL_V2,
+            profile: None,
+        });
+        peer
+    }
+
+    /// Connects the peer which doesn't negotiate the version
+    pub fn connect_legacy(session_service: &Arc<SessionService>) -> Self {
+        let (tx, rx) = session_service.clone().connect_local();
+        Self { tx, rx }
+    }
+
+    /// Connects the peer like a device does, rather than like the in-process Telegram chat
+    pub fn connect_device(session_service: &Arc<SessionService>) -> Self {
+        let (tx, request_rx) = futures::channel::mpsc::unbounded();
+        let (response_tx, rx) = futures::channel::mpsc::unbounded();
+
+        let request_rx = request_rx.map(Ok::<_, std::convert::Infallible>);
+        tokio::spawn(
+            session_service
+                .clone()
+                .handle_socket(response_tx, request_rx, NegotiatedCodec::default(), None, false),
+        );
+
+        let peer = Self { tx, rx };
+        peer.send(&WsRequest::Hello {
+            version: PROTOCOL_V2,
+            profile: None,
+        });
+        peer
+    }
+
+    pub fn send(&self, request: &WsRequest) {
+        self.send_json(serde_json::to_value(request).unwrap());
+    }
+
+    pub fn send_json(&self, request: serde_json::Value) {
+        self.tx.unbounded_send(ws::Message::text(request.to_string())).unwrap();
+    }
+
+    /// Skips other messages until the message of the type arrives, returns its content
+    pub async fn recv(&mut self, message_type: &str) -> serde_json::Value {
+        loop {
+            let message = self.next().await.unwrap_or_else(|| panic!("socket closed before {}", message_type));
+            if message["type"] == message_type {
+                return message["content"].clone();
+            }
+        }
+    }
+
+    /// Next message of the server, `None` once the socket is closed, heartbeat pings are skipped
+    pub async fn next(&mut self) -> Option<serde_json::Value> {
+        loop {
+            let message = tokio::time::timeout(TIMEOUT, self.rx.next())
+                .await
+                .expect("server must answer in time")?;
+            if !message.is_ping() {
+                return Some(serde_json::from_str(message.to_str().unwrap()).unwrap());
+            }
+        }
+    }
+}
+
+/// Relays the data in small parts, like an upload of the peer does
+pub fn relay(data: &[u8]) -> RelayRx {
+    let (mut tx, rx) = relay::relay_channel(4, data.len());
+    let parts = data.chunks(3).map(bytes::Bytes::copy_from_slice).collect::<Vec<_>>();
+    tokio::spawn(async move {
+        for part in parts {
+            let _ = tx.send(Ok(part)).await;
+        }
+    });
+    rx
+}
+
+/// Polls the condition until it returns the value
+pub async fn wait_for<T>(mut condition: impl FnMut() -> Option<T>) -> T {
+    let deadline = tokio::time::Instant::now() + TIMEOUT;
+    loop {
+        if let Some(value) = condition() {
+            return value;
+        }
+        assert!(tokio::time::Instant::now() < deadline, "condition wasn't met in time");
+        tokio::time::delay_for(Duration::from_millis(10)).await;
+    }
+}
+
+const TIMEOUT: Duration = Duration::from_secs(5);
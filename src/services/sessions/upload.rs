@@ -0,0 +1,78 @@
+use futures::Stream;
+use tokio::sync::mpsc::error::TrySendError;
+use uuid::Uuid;
+
+use super::relay::TransferError;
+use super::session::ErrorCode;
+use super::MAX_RELAY_PART_SIZE;
+use crate::prelude::*;
+
+pub type ChunkItem = Result<bytes::Bytes, TransferError>;
+
+/// Uploads which the connection streams in binary websocket frames
+///
+/// Every frame is the request id followed by the chunk of at most `MAX_RELAY_PART_SIZE` bytes.
+/// The owner may send only as many chunks as it was granted credits with `WsResponse::UploadCredits`,
+/// chunks beyond that abort the upload instead of piling up in memory.
+#[derive(Debug, Default)]
+pub struct InbandUploads {
+    uploads: HashMap<Uuid, InbandUpload>,
+}
+
+#[derive(Debug)]
+struct InbandUpload {
+    tx: mpsc::Sender<ChunkItem>,
+    remaining: usize,
+}
+
+impl InbandUploads {
+    /// Registers the upload, returns the stream of chunks which buffers `credits` chunks at most
+    pub fn start(&mut self, request_id: Uuid, size: usize, credits: usize) -> Option<impl Stream<Item = ChunkItem>> {
+        if self.uploads.contains_key(&request_id) {
+            return None;
+        }
+
+        let (tx, mut rx) = mpsc::channel(credits);
+        // Stream of the empty file ends right away
+        if size > 0 {
+            self.uploads.insert(request_id, InbandUpload { tx, remaining: size });
+        }
+
+        Some(futures::stream::poll_fn(move |cx| rx.poll_recv(cx)))
+    }
+
+    /// Passes the data frame to its upload, the upload is finished once all expected bytes are received
+    pub fn push(&mut self, mut frame: bytes::Bytes) -> Result<(), ErrorCode> {
+        if frame.len() < REQUEST_ID_LEN {
+            return Err(ErrorCode::InvalidRequest);
+        }
+
+        let request_id = Uuid::from_slice(&frame.split_to(REQUEST_ID_LEN)).map_err(|_| ErrorCode::InvalidRequest)?;
+        let upload = self.uploads.get_mut(&request_id).ok_or(ErrorCode::UploadNotFound)?;
+
+        if frame.len() > MAX_RELAY_PART_SIZE {
+            self.uploads.remove(&request_id);
+            return Err(ErrorCode::CreditsExceeded);
+        }
+
+        // Extra bytes are still passed on, so the upload fails as too large
+        upload.remaining = upload.remaining.saturating_sub(frame.len());
+        let finished = upload.remaining == 0;
+
+        let result = match upload.tx.try_send(Ok(frame)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(ErrorCode::CreditsExceeded),
+            // Upload has already failed and the owner was notified
+            Err(TrySendError::Closed(_)) => Err(ErrorCode::UploadNotFound),
+        };
+
+        if finished || result.is_err() {
+            // Dropping the sender ends the stream of chunks
+            self.uploads.remove(&request_id);
+        }
+
+        result
+    }
+}
+
+const REQUEST_ID_LEN: usize = 16;
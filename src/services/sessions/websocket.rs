@@ -1,55 +1,374 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use futures::future::Ready;
+use futures::future::Either;
 use futures::task::{Context, Poll};
-use futures::{FutureExt, Stream, StreamExt};
-use warp::ws::{self, WebSocket};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::sync::{watch, Notify};
+use warp::ws;
 
+use super::codec::Codec;
 use crate::prelude::*;
+use crate::settings::{HeartbeatSettings, QueuePolicy, QueueSettings};
 
-pub fn init_connection<Int, ExtReq, ExtRes>(
-    websocket: WebSocket,
-) -> (Arc<Connection<Int, ExtRes>>, EventRx<Int, ExtReq, impl Stream<Item = ExtReq>>)
+/// Turns both halves of the socket into the connection handle and the stream of events,
+/// the stream ends when the peer closes the socket, misses too many pongs or overflows its queue
+///
+/// Besides websockets, the halves can be in-process channels of the peers which live in the server,
+/// such peers are not pinged.
+pub fn init_connection<Int, ExtReq, ExtRes, C, Tx, Rx, E>(
+    ws_tx: Tx,
+    external_rx: Rx,
+    codec: C,
+    heartbeat_settings: Option<&HeartbeatSettings>,
+    queue_settings: &QueueSettings,
+) -> ConnectionHalves<Int, ExtReq, ExtRes, C>
 where
     Int: Send,
-    for<'a> ExtReq: Deserialize<'a> + Send,
-    ExtRes: Serialize + Send,
+    for<'a> ExtReq: Deserialize<'a> + Send + 'static,
+    ExtRes: Serialize + Versioned + Superseding + Send,
+    C: Codec,
+    Tx: Sink<ws::Message> + Send + Unpin + 'static,
+    Tx::Error: std::fmt::Display,
+    Rx: Stream<Item = Result<ws::Message, E>> + Send + 'static,
 {
-    let (ws_tx, external_rx) = websocket.split();
-    let (external_tx, rx) = mpsc::unbounded_channel();
     let (internal_tx, internal_rx) = mpsc::unbounded_channel();
 
-    tokio::task::spawn(rx.forward(ws_tx).map(|result| {
-        if let Err(e) = result {
-            log::trace!("websocket closed: {}", e);
-        }
-    }));
+    let queue = Arc::new(OutboundQueue::new(queue_settings));
+    tokio::task::spawn(run_writer(queue.clone(), ws_tx));
+
+    let heartbeat = Arc::new(Heartbeat::new());
+    if let Some(settings) = heartbeat_settings {
+        tokio::task::spawn(run_heartbeat(heartbeat.clone(), queue.clone(), settings.clone()));
+    }
+
+    let pongs = heartbeat.clone();
+    let aborted = queue.clone();
+    let decoder = codec.clone();
+    // Boxed, so the event stream can be polled without pinning it first
+    let external_rx: ExternalRx<ExtReq> = Box::pin(
+        external_rx
+            .inspect(move |item| {
+                if matches!(item, Ok(message) if message.is_pong()) {
+                    pongs.pong_received();
+                }
+            })
+            .take_until(async move { aborted.aborted().await })
+            .filter_map(move |item| futures::future::ready(decode_external(&decoder, item))),
+    );
 
-    let connection = Arc::new(Connection::new(internal_tx, external_tx));
-    let event_rx = EventRx::new(internal_rx, external_rx.filter_map(filter_external));
+    let connection = Arc::new(Connection::new(internal_tx, queue.clone(), heartbeat, codec));
+    let event_rx = EventRx::new(internal_rx, external_rx, CloseOnDrop(queue));
 
     (connection, event_rx)
 }
 
+/// Writes queued messages to the socket until the queue is closed or aborted
+async fn run_writer<Tx>(queue: Arc<OutboundQueue>, mut ws_tx: Tx)
+where
+    Tx: Sink<ws::Message> + Unpin,
+    Tx::Error: std::fmt::Display,
+{
+    while let Some(message) = queue.pop().await {
+        // Peer which doesn't read can't hold the writer after the connection is aborted
+        match futures::future::select(ws_tx.send(message), Box::pin(queue.aborted())).await {
+            Either::Left((Ok(_), _)) => {}
+            Either::Left((Err(e), _)) => {
+                log::trace!("websocket closed: {}", e);
+                break;
+            }
+            Either::Right(_) => break,
+        }
+    }
+
+    let _ = ws_tx.close().await;
+    queue.abort();
+}
+
+/// Sends pings until the connection is closed, aborts it when the peer stops responding
+async fn run_heartbeat(heartbeat: Arc<Heartbeat>, queue: Arc<OutboundQueue>, settings: HeartbeatSettings) {
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + settings.interval(), settings.interval());
+    loop {
+        interval.tick().await;
+
+        if queue.state() != QueueState::Open {
+            return;
+        }
+
+        let missed = heartbeat.missed_pongs.fetch_add(1, Ordering::Relaxed);
+        if missed >= settings.max_missed_pongs {
+            log::debug!("connection missed {} pongs", missed);
+            queue.abort();
+            return;
+        }
+
+        heartbeat.ping_sent();
+        // Unanswered ping which is still queued is as good as the new one
+        queue.push(ws::Message::ping(Vec::new()), Some(PING_KEY));
+    }
+}
+
+/// Bounded queue of outgoing messages shared by the connection handles and the socket writer
+#[derive(Debug)]
+struct OutboundQueue {
+    messages: std::sync::Mutex<VecDeque<(ws::Message, Option<SupersedeKey>)>>,
+    capacity: usize,
+    policy: QueuePolicy,
+    /// Wakes the writer when a message is pushed or the queue is closed
+    pushed: Notify,
+    state_tx: watch::Sender<QueueState>,
+    state_rx: watch::Receiver<QueueState>,
+    max_depth: AtomicUsize,
+    dropped: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueueState {
+    Open,
+    /// Queued messages are still written, then the socket is closed
+    Closed,
+    /// Queued messages are discarded and the socket is closed right away
+    Aborted,
+}
+
+impl OutboundQueue {
+    fn new(settings: &QueueSettings) -> Self {
+        let (state_tx, state_rx) = watch::channel(QueueState::Open);
+        Self {
+            messages: Default::default(),
+            capacity: settings.capacity.max(1),
+            policy: settings.policy,
+            pushed: Notify::new(),
+            state_tx,
+            state_rx,
+            max_depth: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    /// Queues the message, with the `Coalesce` policy the full queue makes room by replacing
+    /// the queued message with the same key
+    fn push(&self, message: ws::Message, key: Option<SupersedeKey>) {
+        if self.state() != QueueState::Open {
+            return;
+        }
+
+        let mut messages = self.messages.lock().unwrap();
+        if messages.len() >= self.capacity {
+            if self.policy == QueuePolicy::Coalesce {
+                let superseded = key.and_then(|key| messages.iter_mut().find(|(_, queued)| *queued == Some(key)));
+                if let Some(superseded) = superseded {
+                    // Replaced in place, so the new state isn't reordered with the messages queued after the old one
+                    superseded.0 = message;
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            }
+
+            drop(messages);
+            log::debug!("outgoing queue overflow, dropping connection");
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            self.abort();
+            return;
+        }
+
+        messages.push_back((message, key));
+        let queued = QUEUED_MESSAGES.fetch_add(1, Ordering::Relaxed) + 1;
+        MAX_QUEUED_MESSAGES.fetch_max(queued, Ordering::Relaxed);
+        self.max_depth.fetch_max(messages.len(), Ordering::Relaxed);
+        MAX_QUEUE_DEPTH.fetch_max(messages.len(), Ordering::Relaxed);
+        drop(messages);
+
+        self.pushed.notify();
+    }
+
+    async fn pop(&self) -> Option<ws::Message> {
+        loop {
+            {
+                let mut messages = self.messages.lock().unwrap();
+                match self.state() {
+                    QueueState::Aborted => return None,
+                    state => {
+                        if let Some((message, _)) = messages.pop_front() {
+                            QUEUED_MESSAGES.fetch_sub(1, Ordering::Relaxed);
+                            return Some(message);
+                        }
+                        if state == QueueState::Closed {
+                            return None;
+                        }
+                    }
+                }
+            }
+
+            self.pushed.notified().await;
+        }
+    }
+
+    async fn aborted(&self) {
+        let mut state_rx = self.state_rx.clone();
+        while *state_rx.borrow() != QueueState::Aborted {
+            if state_rx.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+
+    #[inline]
+    fn state(&self) -> QueueState {
+        *self.state_rx.borrow()
+    }
+
+    fn close(&self) {
+        if self.state() == QueueState::Open {
+            let _ = self.state_tx.broadcast(QueueState::Closed);
+            self.pushed.notify();
+        }
+    }
+
+    fn abort(&self) {
+        let _ = self.state_tx.broadcast(QueueState::Aborted);
+
+        let mut messages = self.messages.lock().unwrap();
+        QUEUED_MESSAGES.fetch_sub(messages.len(), Ordering::Relaxed);
+        messages.clear();
+        drop(messages);
+
+        self.pushed.notify();
+    }
+
+    fn stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.messages.lock().unwrap().len(),
+            max_depth: self.max_depth.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Lets the writer finish and close the socket once the event stream is dropped
+#[derive(Debug)]
+struct CloseOnDrop(Arc<OutboundQueue>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueStats {
+    /// Messages waiting to be written
+    pub depth: usize,
+    /// Highest depth since the connection was opened
+    pub max_depth: usize,
+    /// Messages dropped because of the overflow
+    pub dropped: usize,
+}
+
+/// Outgoing queues of all connections since the server has started
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct QueueMetrics {
+    /// Messages waiting to be written right now
+    pub queued: usize,
+    /// Highest number of messages waiting at once
+    pub max_queued: usize,
+    /// Highest depth of a single queue
+    pub max_depth: usize,
+}
+
+pub fn queue_metrics() -> QueueMetrics {
+    QueueMetrics {
+        queued: QUEUED_MESSAGES.load(Ordering::Relaxed),
+        max_queued: MAX_QUEUED_MESSAGES.load(Ordering::Relaxed),
+        max_depth: MAX_QUEUE_DEPTH.load(Ordering::Relaxed),
+    }
+}
+
+#[derive(Debug)]
+struct Heartbeat {
+    started_at: Instant,
+    /// Microseconds since `started_at` when the last ping was sent
+    last_ping: AtomicU64,
+    /// Round trip time of the last ping in microseconds, `u64::MAX` if unknown
+    latency: AtomicU64,
+    missed_pongs: AtomicUsize,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            last_ping: AtomicU64::new(0),
+            latency: AtomicU64::new(u64::MAX),
+            missed_pongs: AtomicUsize::new(0),
+        }
+    }
+
+    fn ping_sent(&self) {
+        self.last_ping.store(self.elapsed(), Ordering::Relaxed);
+    }
+
+    fn pong_received(&self) {
+        let latency = self.elapsed().saturating_sub(self.last_ping.load(Ordering::Relaxed));
+        self.latency.store(latency, Ordering::Relaxed);
+        self.missed_pongs.store(0, Ordering::Relaxed);
+        log::trace!("pong received in {} us", latency);
+    }
+
+    fn latency(&self) -> Option<Duration> {
+        match self.latency.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            latency => Some(Duration::from_micros(latency)),
+        }
+    }
+
+    #[inline]
+    fn elapsed(&self) -> u64 {
+        self.started_at.elapsed().as_micros() as u64
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct Connection<Int, ExtRes> {
+pub struct Connection<Int, ExtRes, C> {
     id: ConnectionId,
     internal_tx: InternalTx<Int>,
-    external_tx: WebSocketTx,
+    queue: Arc<OutboundQueue>,
+    heartbeat: Arc<Heartbeat>,
+    /// Negotiated protocol version, shared with the connections restored from this one
+    version: Arc<AtomicU32>,
+    codec: C,
     _marker: std::marker::PhantomData<ExtRes>,
 }
 
-impl<Int, ExtRes> Connection<Int, ExtRes>
+/// Message which has a different shape in some protocol versions
+pub trait Versioned: Clone {
+    /// Returns the message in the shape of the protocol version, `None` if it isn't sent in this version
+    fn for_version(&self, version: ProtocolVersion) -> Option<Cow<'_, Self>>;
+}
+
+/// Message which reports the current state of something, so it makes the queued message about the same thing obsolete
+pub trait Superseding {
+    /// Messages with the same key replace each other when the queue overflows, `None` if the message must be delivered
+    fn supersede_key(&self) -> Option<SupersedeKey>;
+}
+
+impl<Int, ExtRes, C> Connection<Int, ExtRes, C>
 where
     Int: Send,
-    ExtRes: Serialize,
+    ExtRes: Serialize + Versioned + Superseding,
+    C: Codec,
 {
-    fn new(internal_tx: InternalTx<Int>, external_tx: WebSocketTx) -> Self {
+    fn new(internal_tx: InternalTx<Int>, queue: Arc<OutboundQueue>, heartbeat: Arc<Heartbeat>, codec: C) -> Self {
         let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
         Self {
             id,
             internal_tx,
-            external_tx,
+            queue,
+            heartbeat,
+            version: Arc::new(AtomicU32::new(INITIAL_PROTOCOL_VERSION)),
+            codec,
             _marker: Default::default(),
         }
     }
@@ -61,69 +380,174 @@ where
 
     #[inline]
     pub fn send_external(&self, message: &ExtRes) {
-        self.send_external_raw(ws::Message::text(serde_json::to_string(message).unwrap()));
-    }
+        let message = match message.for_version(self.version()) {
+            Some(message) => message,
+            None => return,
+        };
 
-    #[inline]
-    pub fn send_external_raw(&self, message: ws::Message) {
-        let _ = self.external_tx.send(Ok(message));
+        match self.codec.encode(&message) {
+            Ok(frame) => self.queue.push(frame, message.supersede_key()),
+            Err(e) => log::error!("failed to encode message: {}", e),
+        }
     }
 
     #[inline]
     pub fn id(&self) -> ConnectionId {
         self.id
     }
+
+    #[inline]
+    pub fn version(&self) -> ProtocolVersion {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_version(&self, version: ProtocolVersion) {
+        self.version.store(version, Ordering::Relaxed);
+    }
+
+    /// Same connection which is identified as the other one, used to restore the identity after reconnect
+    pub fn with_id(&self, id: ConnectionId) -> Self {
+        Self {
+            id,
+            internal_tx: self.internal_tx.clone(),
+            queue: self.queue.clone(),
+            heartbeat: self.heartbeat.clone(),
+            version: self.version.clone(),
+            codec: self.codec.clone(),
+            _marker: Default::default(),
+        }
+    }
+
+    /// Round trip time of the last answered ping
+    #[inline]
+    pub fn latency(&self) -> Option<Duration> {
+        self.heartbeat.latency()
+    }
+
+    #[inline]
+    pub fn queue_stats(&self) -> QueueStats {
+        self.queue.stats()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum Event<Int, ExtReq> {
     Internal(Int),
     External(ExtReq),
+    /// Text frame which couldn't be parsed as a request
+    Invalid(InvalidRequest),
+    /// Payload of the binary data frame without the tag byte
+    Data(bytes::Bytes),
+}
+
+#[derive(Debug, Clone)]
+pub struct InvalidRequest {
+    /// Id of the request if the frame is at least an object with it
+    pub request_id: Option<String>,
+    pub unknown_type: bool,
+    pub message: String,
 }
 
 #[pin_project]
-#[derive(Debug)]
-pub struct EventRx<Int, ExtReq, ExtReqStr>
-where
-    ExtReqStr: Stream<Item = ExtReq>,
-{
+pub struct EventRx<Int, ExtReq> {
     #[pin]
     internal_rx: InternalRx<Int>,
     #[pin]
-    external_rx: ExtReqStr,
-    _marker: std::marker::PhantomData<ExtReq>,
+    external_rx: ExternalRx<ExtReq>,
+    _queue: CloseOnDrop,
 }
 
-impl<Int, ExtReq, ExtReqStr> EventRx<Int, ExtReq, ExtReqStr>
+impl<Int, ExtReq> EventRx<Int, ExtReq>
 where
     Int: Send,
     for<'a> ExtReq: Deserialize<'a>,
-    ExtReqStr: Stream<Item = ExtReq>,
 {
-    fn new(internal_rx: InternalRx<Int>, external_rx: ExtReqStr) -> Self {
+    fn new(internal_rx: InternalRx<Int>, external_rx: ExternalRx<ExtReq>, queue: CloseOnDrop) -> Self {
         Self {
             internal_rx,
             external_rx,
-            _marker: Default::default(),
+            _queue: queue,
         }
     }
 }
 
-fn filter_external<ExtReq>(item: WebSocketRxItem) -> Ready<Option<ExtReq>>
+/// Decodes frames of the codec and data frames, other frames are skipped
+fn decode_external<ExtReq, C, E>(codec: &C, item: Result<ws::Message, E>) -> Option<ExternalItem<ExtReq>>
 where
-    for<'a> ExtReq: Deserialize<'a> + Send,
+    for<'a> ExtReq: Deserialize<'a>,
+    C: Codec,
 {
-    futures::future::ready(
-        item.ok()
-            .and_then(|message| message.to_str().ok().and_then(|text| serde_json::from_str(text).ok())),
-    )
+    let message = item.ok()?;
+    if message.is_binary() && message.as_bytes().first() == Some(&DATA_FRAME_TAG) {
+        let data = bytes::Bytes::from(message.into_bytes()).slice(1..);
+        return Some(ExternalItem::Data(data));
+    }
+
+    let e = match codec.decode(&message)? {
+        Ok(request) => return Some(ExternalItem::Request(request)),
+        Err(e) => e,
+    };
+
+    let probe = codec.decode::<RequestProbe>(&message).and_then(Result::ok);
+    let unknown_type = probe
+        .as_ref()
+        .and_then(|probe| probe.request_type.as_deref())
+        .is_some_and(is_unknown_type::<ExtReq>);
+
+    Some(ExternalItem::Invalid(InvalidRequest {
+        request_id: probe.and_then(|probe| probe.request_id),
+        unknown_type,
+        message: e.to_string(),
+    }))
 }
 
-impl<Int, ExtReq, ExtReqStr> futures::Stream for EventRx<Int, ExtReq, ExtReqStr>
+/// Extracts the id and the type of the request which can't be parsed
+#[derive(Deserialize)]
+struct RequestProbe {
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(default, rename = "type")]
+    request_type: Option<String>,
+}
+
+/// Checks the type alone, so requests of the known type with the malformed content aren't reported as unknown
+fn is_unknown_type<ExtReq>(request_type: &str) -> bool
+where
+    for<'a> ExtReq: Deserialize<'a>,
+{
+    let tag = serde::de::value::MapDeserializer::<_, TagError>::new(std::iter::once(("type", request_type)));
+    matches!(ExtReq::deserialize(tag), Err(TagError { unknown_variant: true }))
+}
+
+/// Deserialization error which only tells whether the tag names an unknown variant
+#[derive(Debug)]
+struct TagError {
+    unknown_variant: bool,
+}
+
+impl serde::de::Error for TagError {
+    fn custom<T: std::fmt::Display>(_: T) -> Self {
+        Self { unknown_variant: false }
+    }
+
+    fn unknown_variant(_: &str, _: &'static [&'static str]) -> Self {
+        Self { unknown_variant: true }
+    }
+}
+
+impl std::fmt::Display for TagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.unknown_variant { "unknown variant" } else { "invalid tag" })
+    }
+}
+
+impl std::error::Error for TagError {}
+
+impl<Int, ExtReq> futures::Stream for EventRx<Int, ExtReq>
 where
     Int: Send,
     for<'a> ExtReq: Deserialize<'a> + Send,
-    ExtReqStr: Stream<Item = ExtReq>,
 {
     type Item = Event<Int, ExtReq>;
 
@@ -133,32 +557,172 @@ where
     }
 }
 
-fn poll_event<Int, ExtReq, ExtReqStr>(
+fn poll_event<Int, ExtReq>(
     internal_rx: Pin<&mut InternalRx<Int>>,
-    external_rx: Pin<&mut ExtReqStr>,
+    external_rx: Pin<&mut ExternalRx<ExtReq>>,
     cx: &mut Context<'_>,
 ) -> Poll<Option<Event<Int, ExtReq>>>
 where
     Int: Send,
     for<'a> ExtReq: Deserialize<'a> + Send,
-    ExtReqStr: Stream<Item = ExtReq>,
 {
     if let Poll::Ready(Some(item)) = internal_rx.poll_next(cx) {
         return Poll::Ready(Some(Event::Internal(item)));
     };
 
     match external_rx.poll_next(cx) {
-        Poll::Ready(Some(item)) => Poll::Ready(Some(Event::External(item))),
+        Poll::Ready(Some(ExternalItem::Request(item))) => Poll::Ready(Some(Event::External(item))),
+        Poll::Ready(Some(ExternalItem::Invalid(invalid))) => Poll::Ready(Some(Event::Invalid(invalid))),
+        Poll::Ready(Some(ExternalItem::Data(data))) => Poll::Ready(Some(Event::Data(data))),
         Poll::Ready(None) => Poll::Ready(None),
         Poll::Pending => Poll::Pending,
     }
 }
 
-type WebSocketTx = mpsc::UnboundedSender<Result<ws::Message, warp::Error>>;
-type WebSocketRxItem = Result<ws::Message, warp::Error>;
+#[derive(Debug)]
+pub enum ExternalItem<ExtReq> {
+    Request(ExtReq),
+    Invalid(InvalidRequest),
+    Data(bytes::Bytes),
+}
+
+/// Handle which sends messages to the peer and the stream of its events
+pub type ConnectionHalves<Int, ExtReq, ExtRes, C> = (Arc<Connection<Int, ExtRes, C>>, EventRx<Int, ExtReq>);
+/// Decoded frames of the peer
+pub type ExternalRx<ExtReq> = Pin<Box<dyn Stream<Item = ExternalItem<ExtReq>> + Send>>;
+
 type InternalTx<T> = mpsc::UnboundedSender<T>;
 type InternalRx<T> = mpsc::UnboundedReceiver<T>;
 
 pub type ConnectionId = usize;
+pub type ProtocolVersion = u32;
+/// Kind of the message and the id of the thing it reports
+pub type SupersedeKey = (&'static str, u128);
+
+/// First byte of binary frames with raw data, it's never used by MessagePack so such frames
+/// can't be confused with encoded messages
+pub const DATA_FRAME_TAG: u8 = 0xC1;
+
+/// Clients which don't negotiate the version speak the first one
+const INITIAL_PROTOCOL_VERSION: ProtocolVersion = 1;
+
+const PING_KEY: SupersedeKey = ("ping", 0);
 
 static CONNECTION_ID: AtomicUsize = AtomicUsize::new(0);
+static QUEUED_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+static MAX_QUEUED_MESSAGES: AtomicUsize = AtomicUsize::new(0);
+static MAX_QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::sessions::codec::{JsonCodec, MessagePackCodec};
+    use crate::services::sessions::session::WsRequest;
+
+    fn queue(policy: QueuePolicy) -> OutboundQueue {
+        OutboundQueue::new(&QueueSettings { capacity: 2, policy })
+    }
+
+    fn queued(queue: &OutboundQueue) -> Vec<ws::Message> {
+        queue.messages.lock().unwrap().iter().map(|(message, _)| message.clone()).collect()
+    }
+
+    #[test]
+    fn disconnect_on_overflow() {
+        let queue = queue(QueuePolicy::Disconnect);
+        queue.push(ws::Message::text("a"), None);
+        queue.push(ws::Message::text("b"), Some(("peer", 1)));
+        assert_eq!(queue.state(), QueueState::Open);
+
+        queue.push(ws::Message::text("c"), Some(("peer", 1)));
+        assert_eq!(queue.state(), QueueState::Aborted);
+        assert!(queued(&queue).is_empty());
+    }
+
+    #[test]
+    fn coalesce_replaces_superseded_messages_in_place() {
+        let queue = queue(QueuePolicy::Coalesce);
+        queue.push(ws::Message::text("peer 1 away"), Some(("peer", 1)));
+        queue.push(ws::Message::text("file added"), None);
+
+        queue.push(ws::Message::text("peer 1 online"), Some(("peer", 1)));
+        assert_eq!(queue.state(), QueueState::Open);
+        assert_eq!(
+            queued(&queue),
+            vec![ws::Message::text("peer 1 online"), ws::Message::text("file added")]
+        );
+        assert_eq!(queue.stats().dropped, 1);
+    }
+
+    #[test]
+    fn coalesce_keeps_messages_which_are_not_superseded() {
+        let queue = queue(QueuePolicy::Coalesce);
+        queue.push(ws::Message::text("peer 1 away"), Some(("peer", 1)));
+        queue.push(ws::Message::text("connected"), None);
+
+        // Neither the message of the other peer nor the unkeyed one can replace anything
+        queue.push(ws::Message::text("peer 2 away"), Some(("peer", 2)));
+        assert_eq!(queue.state(), QueueState::Aborted);
+
+        let queue = self::queue(QueuePolicy::Coalesce);
+        queue.push(ws::Message::ping(Vec::new()), Some(PING_KEY));
+        queue.push(ws::Message::text("file requested"), None);
+        queue.push(ws::Message::text("file requested"), None);
+        assert_eq!(queue.state(), QueueState::Aborted);
+    }
+
+    #[test]
+    fn metrics_keep_high_water_marks() {
+        let queue = queue(QueuePolicy::Disconnect);
+        queue.push(ws::Message::text("a"), None);
+        queue.push(ws::Message::text("b"), None);
+
+        // Other tests share the counters, so only the lower bounds are known
+        let metrics = queue_metrics();
+        assert!(metrics.max_depth >= 2);
+        assert!(metrics.max_queued >= 2);
+
+        queue.abort();
+        assert!(queue_metrics().max_queued >= 2);
+    }
+
+    fn decode_invalid<C: Codec>(codec: &C, message: ws::Message) -> InvalidRequest {
+        match decode_external::<WsRequest, _, ()>(codec, Ok(message)) {
+            Some(ExternalItem::Invalid(invalid)) => invalid,
+            _ => panic!("frame must be invalid"),
+        }
+    }
+
+    #[test]
+    fn reports_unknown_request_types() {
+        let invalid = decode_invalid(&JsonCodec, ws::Message::text(r#"{"type":"fly","request_id":"1"}"#));
+        assert!(invalid.unknown_type);
+        assert_eq!(invalid.request_id.as_deref(), Some("1"));
+
+        let frame = rmp_serde::to_vec_named(&serde_json::json!({ "type": "fly", "content": {} })).unwrap();
+        assert!(decode_invalid(&MessagePackCodec, ws::Message::binary(frame)).unknown_type);
+    }
+
+    #[test]
+    fn malformed_requests_of_known_types_are_not_unknown() {
+        let invalid = decode_invalid(
+            &JsonCodec,
+            ws::Message::text(r#"{"type":"connect","content":{"phrase":1},"request_id":"2"}"#),
+        );
+        assert!(!invalid.unknown_type);
+        assert_eq!(invalid.request_id.as_deref(), Some("2"));
+
+        // Content can't make the type unknown, even if it mentions unknown variants
+        let invalid = decode_invalid(
+            &JsonCodec,
+            ws::Message::text(
+                r#"{"type":"add_file","content":{"id":"936da01f-9abd-4d9d-80c7-02af85c822a8","name":"a","mime_type":"",
+                "size":1,"hash":{"algorithm":"md5","value":""}}}"#,
+            ),
+        );
+        assert!(!invalid.unknown_type);
+
+        assert!(!decode_invalid(&JsonCodec, ws::Message::text(r#"{"content":{}}"#)).unknown_type);
+        assert!(!decode_invalid(&JsonCodec, ws::Message::text(r#"{"type":5}"#)).unknown_type);
+    }
+}
@@ -0,0 +1,357 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{Datelike, Timelike};
+
+/// Streaming writer of uncompressed ZIP archives
+///
+/// Entry sizes must be known in advance, CRC32 is written after the entry data in the data descriptor.
+/// ZIP64 records are used only when sizes or offsets don't fit the classic format.
+#[derive(Debug)]
+pub struct ZipWriter {
+    entries: Vec<CentralEntry>,
+    offset: u64,
+    time: u16,
+    date: u16,
+}
+
+#[derive(Debug)]
+struct CentralEntry {
+    path: String,
+    size: u64,
+    crc32: u32,
+    offset: u64,
+}
+
+impl ZipWriter {
+    pub fn new() -> Self {
+        let now = chrono::Local::now();
+        let time = ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16;
+        let year = (now.year().max(1980) - 1980) as u32;
+        let date = ((year << 9) | (now.month() << 5) | now.day()) as u16;
+
+        Self {
+            entries: Vec::new(),
+            offset: 0,
+            time,
+            date,
+        }
+    }
+
+    /// Computes the exact size of the archive with the specified entries
+    pub fn archive_size<'a, I>(entries: I) -> u64
+    where
+        I: IntoIterator<Item = (&'a str, u64)>,
+    {
+        let mut writer = Self::new();
+        let mut total = 0;
+        for (path, size) in entries {
+            total += writer.start_entry(path, size).len() as u64 + size;
+            total += writer.finish_entry(0).len() as u64;
+        }
+        total + writer.finish().len() as u64
+    }
+
+    /// Returns local file header of the new entry, exactly `size` bytes of data must follow
+    pub fn start_entry(&mut self, path: &str, size: u64) -> Bytes {
+        let zip64 = size >= ZIP64_LIMIT;
+
+        let mut buffer = BytesMut::with_capacity(LOCAL_HEADER_LEN + path.len() + ZIP64_LOCAL_EXTRA_LEN);
+        buffer.put_u32_le(LOCAL_HEADER_SIGNATURE);
+        buffer.put_u16_le(version_needed(zip64));
+        buffer.put_u16_le(FLAGS);
+        buffer.put_u16_le(METHOD_STORE);
+        buffer.put_u16_le(self.time);
+        buffer.put_u16_le(self.date);
+        // CRC32 and sizes are written in the data descriptor
+        buffer.put_u32_le(0);
+        buffer.put_u32_le(if zip64 { u32::MAX } else { 0 });
+        buffer.put_u32_le(if zip64 { u32::MAX } else { 0 });
+        buffer.put_u16_le(path.len() as u16);
+        buffer.put_u16_le(if zip64 { ZIP64_LOCAL_EXTRA_LEN as u16 } else { 0 });
+        buffer.put_slice(path.as_bytes());
+        if zip64 {
+            buffer.put_u16_le(ZIP64_EXTRA_ID);
+            buffer.put_u16_le(16);
+            buffer.put_u64_le(0);
+            buffer.put_u64_le(0);
+        }
+
+        self.entries.push(CentralEntry {
+            path: path.to_owned(),
+            size,
+            crc32: 0,
+            offset: self.offset,
+        });
+        self.offset += buffer.len() as u64 + size;
+
+        buffer.freeze()
+    }
+
+    /// Returns data descriptor of the current entry
+    pub fn finish_entry(&mut self, crc32: u32) -> Bytes {
+        let entry = self.entries.last_mut().expect("entry must be started");
+        entry.crc32 = crc32;
+        let zip64 = entry.size >= ZIP64_LIMIT;
+
+        let mut buffer = BytesMut::with_capacity(24);
+        buffer.put_u32_le(DATA_DESCRIPTOR_SIGNATURE);
+        buffer.put_u32_le(crc32);
+        if zip64 {
+            buffer.put_u64_le(entry.size);
+            buffer.put_u64_le(entry.size);
+        } else {
+            buffer.put_u32_le(entry.size as u32);
+            buffer.put_u32_le(entry.size as u32);
+        }
+        self.offset += buffer.len() as u64;
+
+        buffer.freeze()
+    }
+
+    /// Returns central directory and end of the archive
+    pub fn finish(self) -> Bytes {
+        let mut buffer = BytesMut::new();
+
+        let directory_offset = self.offset;
+        for entry in &self.entries {
+            let size_overflow = entry.size >= ZIP64_LIMIT;
+            let offset_overflow = entry.offset >= ZIP64_LIMIT;
+
+            let mut extra = BytesMut::new();
+            if size_overflow || offset_overflow {
+                let sizes_len = if size_overflow { 16 } else { 0 };
+                let offset_len = if offset_overflow { 8 } else { 0 };
+                extra.put_u16_le(ZIP64_EXTRA_ID);
+                extra.put_u16_le(sizes_len + offset_len);
+                if size_overflow {
+                    extra.put_u64_le(entry.size);
+                    extra.put_u64_le(entry.size);
+                }
+                if offset_overflow {
+                    extra.put_u64_le(entry.offset);
+                }
+            }
+
+            let size = if size_overflow { u32::MAX } else { entry.size as u32 };
+
+            buffer.put_u32_le(CENTRAL_HEADER_SIGNATURE);
+            buffer.put_u16_le(version_needed(true));
+            buffer.put_u16_le(version_needed(size_overflow || offset_overflow));
+            buffer.put_u16_le(FLAGS);
+            buffer.put_u16_le(METHOD_STORE);
+            buffer.put_u16_le(self.time);
+            buffer.put_u16_le(self.date);
+            buffer.put_u32_le(entry.crc32);
+            buffer.put_u32_le(size);
+            buffer.put_u32_le(size);
+            buffer.put_u16_le(entry.path.len() as u16);
+            buffer.put_u16_le(extra.len() as u16);
+            buffer.put_u16_le(0); // comment length
+            buffer.put_u16_le(0); // disk number
+            buffer.put_u16_le(0); // internal attributes
+            buffer.put_u32_le(0); // external attributes
+            buffer.put_u32_le(if offset_overflow { u32::MAX } else { entry.offset as u32 });
+            buffer.put_slice(entry.path.as_bytes());
+            buffer.put_slice(&extra);
+        }
+
+        let directory_size = buffer.len() as u64;
+        let entry_count = self.entries.len() as u64;
+
+        let zip64 = entry_count >= u16::MAX as u64 || directory_size >= ZIP64_LIMIT || directory_offset >= ZIP64_LIMIT;
+        if zip64 {
+            let record_offset = directory_offset + directory_size;
+
+            buffer.put_u32_le(ZIP64_END_SIGNATURE);
+            buffer.put_u64_le(44); // size of the remaining record
+            buffer.put_u16_le(version_needed(true));
+            buffer.put_u16_le(version_needed(true));
+            buffer.put_u32_le(0); // disk number
+            buffer.put_u32_le(0); // disk with central directory
+            buffer.put_u64_le(entry_count);
+            buffer.put_u64_le(entry_count);
+            buffer.put_u64_le(directory_size);
+            buffer.put_u64_le(directory_offset);
+
+            buffer.put_u32_le(ZIP64_LOCATOR_SIGNATURE);
+            buffer.put_u32_le(0); // disk with zip64 end record
+            buffer.put_u64_le(record_offset);
+            buffer.put_u32_le(1); // total number of disks
+        }
+
+        buffer.put_u32_le(END_SIGNATURE);
+        buffer.put_u16_le(0); // disk number
+        buffer.put_u16_le(0); // disk with central directory
+        buffer.put_u16_le(entry_count.min(u16::MAX as u64) as u16);
+        buffer.put_u16_le(entry_count.min(u16::MAX as u64) as u16);
+        buffer.put_u32_le(directory_size.min(u32::MAX as u64) as u32);
+        buffer.put_u32_le(directory_offset.min(u32::MAX as u64) as u32);
+        buffer.put_u16_le(0); // comment length
+
+        buffer.freeze()
+    }
+}
+
+impl Default for ZipWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Checks that the path is relative and doesn't escape the archive root
+pub fn is_valid_path(path: &str) -> bool {
+    !path.is_empty()
+        && path.len() < u16::MAX as usize
+        && !path.starts_with('/')
+        && !path.contains('\\')
+        && path.split('/').all(|part| !part.is_empty() && part != "." && part != "..")
+}
+
+#[inline]
+fn version_needed(zip64: bool) -> u16 {
+    if zip64 {
+        45
+    } else {
+        20
+    }
+}
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const ZIP64_END_SIGNATURE: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIGNATURE: u32 = 0x0706_4b50;
+const END_SIGNATURE: u32 = 0x0605_4b50;
+
+const LOCAL_HEADER_LEN: usize = 30;
+const ZIP64_LOCAL_EXTRA_LEN: usize = 20;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+const ZIP64_LIMIT: u64 = u32::MAX as u64;
+
+/// Data descriptor is used and paths are UTF-8
+const FLAGS: u16 = 0x0008 | 0x0800;
+const METHOD_STORE: u16 = 0;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn read_u16(data: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([data[at], data[at + 1]])
+    }
+
+    fn read_u32(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    fn write_archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new();
+        let mut archive = Vec::new();
+        for (path, data) in entries {
+            archive.extend_from_slice(&writer.start_entry(path, data.len() as u64));
+            archive.extend_from_slice(data);
+            archive.extend_from_slice(&writer.finish_entry(crc32fast::hash(data)));
+        }
+        archive.extend_from_slice(&writer.finish());
+        archive
+    }
+
+    #[test]
+    fn archive_size_matches_streamed_bytes() {
+        let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), ("dir/b.bin", &[0u8; 1000]), ("empty", b"")];
+
+        let archive = write_archive(entries);
+        let expected = ZipWriter::archive_size(entries.iter().map(|(path, data)| (*path, data.len() as u64)));
+
+        assert_eq!(archive.len() as u64, expected);
+        assert_eq!(write_archive(&[]).len() as u64, ZipWriter::archive_size(Vec::new()));
+    }
+
+    #[test]
+    fn end_record_points_to_central_directory() {
+        let entries: &[(&str, &[u8])] = &[("a.txt", b"hello"), ("b.txt", b"world!")];
+        let archive = write_archive(entries);
+
+        let end = archive.len() - 22;
+        assert_eq!(read_u32(&archive, end), END_SIGNATURE);
+        assert_eq!(read_u16(&archive, end + 10), 2);
+
+        let directory_size = read_u32(&archive, end + 12) as usize;
+        let directory_offset = read_u32(&archive, end + 16) as usize;
+        assert_eq!(directory_offset + directory_size, end);
+        assert_eq!(read_u32(&archive, directory_offset), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(read_u32(&archive, directory_offset + 16), crc32fast::hash(b"hello"));
+
+        // Second entry starts right after the first data descriptor
+        let second = directory_offset + 46 + "a.txt".len();
+        assert_eq!(read_u32(&archive, second + 42) as usize, LOCAL_HEADER_LEN + 5 + 5 + 16);
+    }
+
+    #[test]
+    fn classic_records_below_zip64_limit() {
+        let mut writer = ZipWriter::new();
+        let header = writer.start_entry("big", ZIP64_LIMIT - 1);
+        assert_eq!(header.len(), LOCAL_HEADER_LEN + 3);
+        assert_eq!(read_u16(&header, 4), version_needed(false));
+        assert_eq!(writer.finish_entry(0).len(), 16);
+
+        // Entry itself fits, but the central directory starts beyond the classic limit
+        let end = writer.finish();
+        assert_eq!(read_u32(&end, 20), (ZIP64_LIMIT - 1) as u32);
+        assert_eq!(read_u16(&end, 30), 0);
+        assert_eq!(read_u32(&end, 46 + 3), ZIP64_END_SIGNATURE);
+        assert_eq!(end.len(), 46 + 3 + 56 + 20 + 22);
+    }
+
+    #[test]
+    fn zip64_records_at_limit() {
+        let mut writer = ZipWriter::new();
+        let header = writer.start_entry("big", ZIP64_LIMIT);
+        assert_eq!(header.len(), LOCAL_HEADER_LEN + 3 + ZIP64_LOCAL_EXTRA_LEN);
+        assert_eq!(read_u16(&header, 4), version_needed(true));
+        assert_eq!(read_u32(&header, 18), u32::MAX);
+        assert_eq!(read_u16(&header, 30 + 3), ZIP64_EXTRA_ID);
+        assert_eq!(writer.finish_entry(0).len(), 24);
+
+        // Entry after the big one has an offset beyond the classic limit
+        writer.start_entry("small", 1);
+        writer.finish_entry(0);
+
+        let end = writer.finish();
+        let second_offset = ZIP64_LIMIT + (LOCAL_HEADER_LEN + 3 + ZIP64_LOCAL_EXTRA_LEN + 24) as u64;
+        let directory_offset = second_offset + (LOCAL_HEADER_LEN + 5 + 1 + 16) as u64;
+
+        // Big entry stores both sizes in the extra field
+        assert_eq!(read_u32(&end, 0), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(read_u32(&end, 20), u32::MAX);
+        assert_eq!(read_u16(&end, 30), 20);
+        assert_eq!(read_u16(&end, 46 + 3), ZIP64_EXTRA_ID);
+        assert_eq!(read_u16(&end, 46 + 3 + 2), 16);
+
+        // Small entry stores only its offset in the extra field
+        let second = 46 + 3 + 20;
+        assert_eq!(read_u32(&end, second), CENTRAL_HEADER_SIGNATURE);
+        assert_eq!(read_u32(&end, second + 20), 1);
+        assert_eq!(read_u32(&end, second + 42), u32::MAX);
+        assert_eq!(read_u16(&end, second + 30), 12);
+        assert_eq!(read_u16(&end, second + 46 + 5 + 2), 8);
+        let extra = second + 46 + 5 + 4;
+        assert_eq!(u64::from_le_bytes(end[extra..extra + 8].try_into().unwrap()), second_offset);
+
+        let directory_size = (second + 46 + 5 + 12) as u64;
+        let record = directory_size as usize;
+        assert_eq!(read_u32(&end, record), ZIP64_END_SIGNATURE);
+
+        let locator = record + 56;
+        assert_eq!(read_u32(&end, locator), ZIP64_LOCATOR_SIGNATURE);
+        assert_eq!(
+            u64::from_le_bytes(end[locator + 8..locator + 16].try_into().unwrap()),
+            directory_offset + directory_size
+        );
+
+        let classic_end = locator + 20;
+        assert_eq!(read_u32(&end, classic_end), END_SIGNATURE);
+        assert_eq!(read_u32(&end, classic_end + 16), u32::MAX);
+        assert_eq!(end.len(), classic_end + 22);
+    }
+}
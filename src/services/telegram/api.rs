@@ -0,0 +1,335 @@
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use hyper::client::HttpConnector;
+use hyper::{Body, Method, Request};
+use hyper_rustls::HttpsConnector;
+use serde::de::DeserializeOwned;
+
+use crate::prelude::*;
+use crate::services::sessions::RelayRx;
+use crate::settings::TelegramSettings;
+
+pub type ChatId = i64;
+pub type FileStream = BoxStream<'static, Result<bytes::Bytes>>;
+
+/// Subset of the Telegram Bot API used by the bot
+#[async_trait::async_trait]
+pub trait BotApi: Send + Sync {
+    /// Long polls new updates starting from `offset`
+    async fn get_updates(&self, offset: i64) -> Result<Vec<Update>>;
+
+    async fn send_message(&self, chat_id: ChatId, text: &str) -> Result<()>;
+
+    /// Streams the document to the chat without buffering it
+    async fn send_document(&self, chat_id: ChatId, name: &str, mime_type: &str, data: RelayRx) -> Result<()>;
+
+    /// Resolves the file id into the path which can be downloaded
+    async fn get_file(&self, file_id: &str) -> Result<File>;
+
+    async fn download_file(&self, file_path: &str) -> Result<FileStream>;
+}
+
+/// Bot API over HTTPS, the base url can be changed to a local Bot API server or a fake one
+pub struct HttpBotApi {
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+    api_url: String,
+    token: String,
+    poll_timeout_secs: u64,
+}
+
+impl HttpBotApi {
+    pub fn new(settings: &TelegramSettings) -> Self {
+        Self {
+            client: hyper::Client::builder().build(HttpsConnector::new()),
+            api_url: settings.api_url.trim_end_matches('/').to_owned(),
+            token: settings.token.clone(),
+            poll_timeout_secs: settings.poll_timeout().as_secs(),
+        }
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, content_type: &str, body: Body) -> Result<T> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(format!("{}/bot{}/{}", self.api_url, self.token, method))
+            .header(http::header::CONTENT_TYPE, content_type)
+            .body(body)?;
+
+        let response = self.client.request(request).await?;
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        match serde_json::from_slice::<ApiResponse<T>>(&body)? {
+            ApiResponse {
+                ok: true,
+                result: Some(result),
+                ..
+            } => Ok(result),
+            ApiResponse { description, .. } => Err(anyhow::anyhow!(
+                "{} failed: {}",
+                method,
+                description.unwrap_or_else(|| "unknown error".to_owned())
+            )),
+        }
+    }
+
+    async fn call_json<T: DeserializeOwned, P: Serialize>(&self, method: &str, params: &P) -> Result<T> {
+        let body = serde_json::to_vec(params)?;
+        self.call(method, "application/json", Body::from(body)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl BotApi for HttpBotApi {
+    async fn get_updates(&self, offset: i64) -> Result<Vec<Update>> {
+        let params = serde_json::json!({
+            "offset": offset,
+            "timeout": self.poll_timeout_secs,
+            "allowed_updates": ["message"],
+        });
+        self.call_json("getUpdates", &params).await
+    }
+
+    async fn send_message(&self, chat_id: ChatId, text: &str) -> Result<()> {
+        let params = serde_json::json!({ "chat_id": chat_id, "text": text });
+        self.call_json::<serde_json::Value, _>("sendMessage", &params).await?;
+        Ok(())
+    }
+
+    async fn send_document(&self, chat_id: ChatId, name: &str, mime_type: &str, data: RelayRx) -> Result<()> {
+        let boundary = uuid::Uuid::new_v4().to_simple().to_string();
+        let head = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n{chat_id}\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"document\"; filename=\"{name}\"\r\n\
+             Content-Type: {mime_type}\r\n\r\n",
+            boundary = boundary,
+            chat_id = chat_id,
+            name = name.replace(['"', '\r', '\n'], "_"),
+            mime_type = mime_type,
+        );
+        let tail = format!("\r\n--{}--\r\n", boundary);
+
+        let body = futures::stream::once(futures::future::ready(Ok(bytes::Bytes::from(head))))
+            .chain(data.map(|part| part.map_err(anyhow::Error::from)))
+            .chain(futures::stream::once(futures::future::ready(Ok(bytes::Bytes::from(tail)))))
+            .map(|part: Result<bytes::Bytes>| part.map_err(Box::<dyn std::error::Error + Send + Sync>::from));
+
+        let content_type = format!("multipart/form-data; boundary={}", boundary);
+        self.call::<serde_json::Value>("sendDocument", &content_type, Body::wrap_stream(body))
+            .await?;
+        Ok(())
+    }
+
+    async fn get_file(&self, file_id: &str) -> Result<File> {
+        let params = serde_json::json!({ "file_id": file_id });
+        self.call_json("getFile", &params).await
+    }
+
+    async fn download_file(&self, file_path: &str) -> Result<FileStream> {
+        let uri = format!("{}/file/bot{}/{}", self.api_url, self.token, file_path);
+        let response = self.client.get(uri.parse()?).await?;
+        anyhow::ensure!(
+            response.status().is_success(),
+            "file download failed with status {}",
+            response.status()
+        );
+
+        Ok(response.into_body().map(|part| part.map_err(anyhow::Error::from)).boxed())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ApiResponse<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Update {
+    pub update_id: i64,
+    pub message: Option<Message>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Message {
+    pub chat: Chat,
+    pub text: Option<String>,
+    pub document: Option<Document>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Chat {
+    pub id: ChatId,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Document {
+    pub file_id: String,
+    pub file_name: Option<String>,
+    pub mime_type: Option<String>,
+    pub file_size: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct File {
+    pub file_size: Option<usize>,
+    /// Missing if the file is too large to be downloaded by bots
+    pub file_path: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, StatusCode};
+
+    use super::*;
+    use crate::services::sessions::testing;
+
+    /// Requests received by the fake Bot API with their content types and bodies
+    type Calls = Arc<std::sync::Mutex<Vec<(String, String, bytes::Bytes)>>>;
+
+    /// Minimal Bot API which answers like Telegram does and serves a single file
+    async fn fake_bot_api(calls: Calls, request: Request<Body>) -> Response<Body> {
+        let json = |status, body: serde_json::Value| {
+            Response::builder()
+                .status(status)
+                .header(http::header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        let path = request.uri().path().to_owned();
+        if request.method() == Method::GET {
+            return match path.as_str() {
+                "/file/botTOKEN/documents/file_1.txt" => Response::new(Body::from("document data")),
+                _ => Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap(),
+            };
+        }
+
+        let content_type = request
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_owned();
+        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+        calls.lock().unwrap().push((path.clone(), content_type, body));
+
+        let result = match path.as_str() {
+            "/botTOKEN/getUpdates" => serde_json::json!([
+                { "update_id": 7, "message": { "message_id": 1, "chat": { "id": 42, "type": "private" }, "text": "/join" } },
+                {
+                    "update_id": 8,
+                    "message": {
+                        "message_id": 2,
+                        "chat": { "id": 42, "type": "private" },
+                        "document": { "file_id": "doc-1", "file_unique_id": "u", "file_name": "doc.txt", "file_size": 13 }
+                    }
+                },
+                { "update_id": 9, "edited_message": { "message_id": 1 } }
+            ]),
+            "/botTOKEN/getFile" => serde_json::json!({ "file_id": "doc-1", "file_size": 13, "file_path": "documents/file_1.txt" }),
+            "/botTOKEN/sendDocument" => serde_json::json!({ "message_id": 3 }),
+            _ => {
+                return json(
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({ "ok": false, "error_code": 400, "description": "Bad Request: chat not found" }),
+                )
+            }
+        };
+        json(StatusCode::OK, serde_json::json!({ "ok": true, "result": result }))
+    }
+
+    fn serve() -> (HttpBotApi, Calls) {
+        let calls = Calls::default();
+
+        let make_service = make_service_fn({
+            let calls = calls.clone();
+            move |_| {
+                let calls = calls.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| {
+                        let calls = calls.clone();
+                        async move { Ok::<_, Infallible>(fake_bot_api(calls, request).await) }
+                    }))
+                }
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let api_url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let api = HttpBotApi::new(&TelegramSettings {
+            token: "TOKEN".to_owned(),
+            api_url,
+            poll_timeout_secs: 30,
+        });
+        (api, calls)
+    }
+
+    #[tokio::test]
+    async fn gets_updates() {
+        let (api, calls) = serve();
+
+        let updates = api.get_updates(7).await.unwrap();
+        assert_eq!(updates.iter().map(|update| update.update_id).collect::<Vec<_>>(), vec![7, 8, 9]);
+        assert_eq!(updates[0].message.as_ref().unwrap().text.as_deref(), Some("/join"));
+        let document = updates[1].message.as_ref().unwrap().document.as_ref().unwrap();
+        assert_eq!((document.file_id.as_str(), document.file_size), ("doc-1", Some(13)));
+        assert!(updates[2].message.is_none());
+
+        let (_, content_type, body) = calls.lock().unwrap()[0].clone();
+        assert_eq!(content_type, "application/json");
+        let params = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(params["offset"], 7);
+        assert_eq!(params["timeout"], 30);
+    }
+
+    #[tokio::test]
+    async fn reports_api_errors() {
+        let (api, _) = serve();
+
+        let e = api.send_message(1, "hello").await.unwrap_err();
+        assert_eq!(e.to_string(), "sendMessage failed: Bad Request: chat not found");
+    }
+
+    #[tokio::test]
+    async fn sends_document_as_multipart() {
+        let (api, calls) = serve();
+
+        api.send_document(42, "a \"b\".txt", "text/plain", testing::relay(b"document data"))
+            .await
+            .unwrap();
+
+        let (path, content_type, body) = calls.lock().unwrap()[0].clone();
+        assert_eq!(path, "/botTOKEN/sendDocument");
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+        let expected = format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"chat_id\"\r\n\r\n42\r\n\
+             --{boundary}\r\nContent-Disposition: form-data; name=\"document\"; filename=\"a _b_.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\ndocument data\r\n--{boundary}--\r\n",
+            boundary = boundary
+        );
+        assert_eq!(std::str::from_utf8(&body).unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn downloads_file_by_its_path() {
+        let (api, _) = serve();
+
+        let file = api.get_file("doc-1").await.unwrap();
+        assert_eq!(file.file_size, Some(13));
+        let file_path = file.file_path.unwrap();
+
+        let mut data = Vec::new();
+        let mut parts = api.download_file(&file_path).await.unwrap();
+        while let Some(part) = parts.next().await {
+            data.extend_from_slice(&part.unwrap());
+        }
+        assert_eq!(data, b"document data");
+
+        let e = api.download_file("documents/missing.txt").await.err().unwrap();
+        assert_eq!(e.to_string(), "file download failed with status 404 Not Found");
+    }
+}
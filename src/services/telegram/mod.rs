@@ -0,0 +1,338 @@
+mod api;
+mod peer;
+
+use std::time::Duration;
+
+pub use self::api::{BotApi, HttpBotApi};
+use self::api::{ChatId, Message};
+use self::peer::{ChatCommand, ChatPeer};
+use crate::prelude::*;
+use crate::services::sessions::SessionService;
+use crate::settings::TelegramSettings;
+
+/// Telegram bot which pairs chats with browser sessions
+///
+/// The chat joins the session with `/join <phrase>` as one more peer: files added by others
+/// are sent to the chat as documents and documents sent to the bot are added to the session.
+pub struct TelegramService {
+    api: Arc<dyn BotApi>,
+    session_service: Arc<SessionService>,
+}
+
+impl TelegramService {
+    pub fn new(settings: &TelegramSettings, session_service: Arc<SessionService>) -> Arc<Self> {
+        Self::with_api(Arc::new(HttpBotApi::new(settings)), session_service)
+    }
+
+    pub fn with_api(api: Arc<dyn BotApi>, session_service: Arc<SessionService>) -> Arc<Self> {
+        Arc::new(Self { api, session_service })
+    }
+
+    /// Long polls updates and dispatches them to the chats
+    pub async fn run(self: Arc<Self>) {
+        let mut chats: HashMap<ChatId, mpsc::UnboundedSender<ChatCommand>> = HashMap::new();
+        let mut offset = 0;
+
+        loop {
+            let updates = match self.api.get_updates(offset).await {
+                Ok(updates) => updates,
+                Err(e) => {
+                    log::warn!("failed to get telegram updates: {}", e);
+                    tokio::time::delay_for(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                offset = offset.max(update.update_id + 1);
+                if let Some(message) = update.message {
+                    self.handle_message(&mut chats, message).await;
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&self, chats: &mut HashMap<ChatId, mpsc::UnboundedSender<ChatCommand>>, message: Message) {
+        let chat_id = message.chat.id;
+
+        if let Some(document) = message.document {
+            let sent = chats
+                .get(&chat_id)
+                .map(|chat| chat.send(ChatCommand::AddDocument(document)).is_ok())
+                .unwrap_or_default();
+            if !sent {
+                chats.remove(&chat_id);
+                self.reply(chat_id, NOT_JOINED).await;
+            }
+            return;
+        }
+
+        let text = message.text.unwrap_or_default();
+        let mut words = text.split_whitespace();
+        // Commands in groups are suffixed with the bot name
+        let command = words.next().unwrap_or_default().split('@').next().unwrap_or_default();
+
+        match command {
+            "/join" => {
+                let phrase = words.map(str::to_lowercase).collect::<Vec<_>>().join(" ");
+                if phrase.is_empty() {
+                    self.reply(chat_id, "Usage: /join <phrase>").await;
+                    return;
+                }
+
+                // Previous session of the chat is left once its commands are dropped
+                let (tx, rx) = mpsc::unbounded_channel();
+                chats.insert(chat_id, tx);
+
+                let peer = ChatPeer::new(chat_id, self.api.clone(), self.session_service.clone());
+                tokio::spawn(peer.run(phrase, rx));
+            }
+            "/leave" => {
+                if chats.remove(&chat_id).is_some() {
+                    self.reply(chat_id, "Left the session").await;
+                } else {
+                    self.reply(chat_id, NOT_JOINED).await;
+                }
+            }
+            _ => self.reply(chat_id, HELP).await,
+        }
+    }
+
+    async fn reply(&self, chat_id: ChatId, text: &str) {
+        if let Err(e) = self.api.send_message(chat_id, text).await {
+            log::warn!("failed to send telegram message: {}", e);
+        }
+    }
+}
+
+const HELP: &str = "Send /join <phrase> with the phrase shown in the browser to receive its files here. \
+                    Documents sent to the bot are shared with the session. /leave stops sharing.";
+const NOT_JOINED: &str = "Join a session first with /join <phrase>";
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use uuid::Uuid;
+
+    use super::api::{Chat, Document, File, FileStream, Update};
+    use super::*;
+    use crate::services::sessions::testing::{self, TestPeer};
+    use crate::services::sessions::{RangeSpec, RelayRx, WsRequest};
+
+    /// Records everything the bot sends and serves documents from memory
+    #[derive(Default)]
+    struct FakeBotApi {
+        messages: std::sync::Mutex<Vec<(ChatId, String)>>,
+        documents: std::sync::Mutex<Vec<(ChatId, String, Vec<u8>)>>,
+        /// Contents of the files by their ids, ids are used as paths as well
+        files: HashMap<String, Vec<u8>>,
+    }
+
+    impl FakeBotApi {
+        fn with_file(file_id: &str, data: &[u8]) -> Self {
+            let mut files = HashMap::new();
+            files.insert(file_id.to_owned(), data.to_vec());
+            Self {
+                files,
+                ..Default::default()
+            }
+        }
+
+        async fn wait_for_message(&self, text: &str) {
+            testing::wait_for(|| {
+                let messages = self.messages.lock().unwrap();
+                messages
+                    .iter()
+                    .any(|(chat_id, message)| *chat_id == CHAT_ID && message == text)
+                    .then_some(())
+            })
+            .await
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl BotApi for FakeBotApi {
+        async fn get_updates(&self, _offset: i64) -> Result<Vec<Update>> {
+            futures::future::pending().await
+        }
+
+        async fn send_message(&self, chat_id: ChatId, text: &str) -> Result<()> {
+            self.messages.lock().unwrap().push((chat_id, text.to_owned()));
+            Ok(())
+        }
+
+        async fn send_document(&self, chat_id: ChatId, name: &str, _mime_type: &str, mut data: RelayRx) -> Result<()> {
+            let mut document = Vec::new();
+            while let Some(part) = data.next().await {
+                document.extend_from_slice(&part?);
+            }
+            self.documents.lock().unwrap().push((chat_id, name.to_owned(), document));
+            Ok(())
+        }
+
+        async fn get_file(&self, file_id: &str) -> Result<File> {
+            let data = self.files.get(file_id).ok_or_else(|| anyhow::anyhow!("file not found"))?;
+            Ok(File {
+                file_size: Some(data.len()),
+                file_path: Some(file_id.to_owned()),
+            })
+        }
+
+        async fn download_file(&self, file_path: &str) -> Result<FileStream> {
+            let data = self.files.get(file_path).ok_or_else(|| anyhow::anyhow!("file not found"))?;
+            // Small parts, so ranges start and end inside of them
+            let parts = data
+                .chunks(3)
+                .map(|part| Ok(bytes::Bytes::copy_from_slice(part)))
+                .collect::<Vec<_>>();
+            Ok(futures::stream::iter(parts).boxed())
+        }
+    }
+
+    fn message(text: Option<&str>, document: Option<Document>) -> Message {
+        Message {
+            chat: Chat { id: CHAT_ID },
+            text: text.map(str::to_owned),
+            document,
+        }
+    }
+
+    /// Browser peer which shows the phrase and the seed of the session the chat has joined
+    struct Joined {
+        service: Arc<TelegramService>,
+        chats: HashMap<ChatId, mpsc::UnboundedSender<ChatCommand>>,
+        browser: TestPeer,
+        seed: String,
+    }
+
+    async fn join(api: Arc<FakeBotApi>) -> Joined {
+        let session_service = testing::session_service();
+        let mut browser = TestPeer::connect(&session_service);
+        let phrase = browser.recv("created").await["phrase"].as_str().unwrap().to_owned();
+
+        let service = TelegramService::with_api(api.clone(), session_service);
+        let mut chats = HashMap::new();
+        let join = format!("/join@fbox_bot {}", phrase.to_uppercase());
+        service.handle_message(&mut chats, message(Some(&join), None)).await;
+
+        let seed = browser.recv("connected").await["seed"].as_str().unwrap().to_owned();
+        api.wait_for_message("Joined the session, its files will be sent here").await;
+
+        Joined {
+            service,
+            chats,
+            browser,
+            seed,
+        }
+    }
+
+    #[tokio::test]
+    async fn join_pairs_chat_with_session() {
+        let api = Arc::new(FakeBotApi::default());
+        let Joined { mut browser, .. } = join(api.clone()).await;
+
+        let peers = browser.recv("peer_list").await["peers"].clone();
+        let bot = peers
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|peer| peer["name"] == "Telegram")
+            .expect("chat must be in the roster");
+        assert_eq!(bot["device_type"], "bot");
+    }
+
+    #[tokio::test]
+    async fn join_requires_known_phrase() {
+        let api = Arc::new(FakeBotApi::default());
+        let service = TelegramService::with_api(api.clone(), testing::session_service());
+        let mut chats = HashMap::new();
+
+        service.handle_message(&mut chats, message(Some("/leave"), None)).await;
+        api.wait_for_message(NOT_JOINED).await;
+
+        service.handle_message(&mut chats, message(Some("/join"), None)).await;
+        api.wait_for_message("Usage: /join <phrase>").await;
+
+        service
+            .handle_message(&mut chats, message(Some("/join no such phrase"), None))
+            .await;
+        api.wait_for_message("Session with this phrase is not found").await;
+    }
+
+    #[tokio::test]
+    async fn session_files_are_sent_to_chat() {
+        let api = Arc::new(FakeBotApi::default());
+        // Chat leaves the session once its commands are dropped
+        let Joined {
+            service,
+            chats: _chats,
+            mut browser,
+            seed,
+        } = join(api.clone()).await;
+
+        let id = Uuid::new_v4();
+        browser.send(&WsRequest::AddFile {
+            id,
+            name: "notes.txt".to_owned(),
+            mime_type: "text/plain".to_owned(),
+            size: 5,
+            hash: None,
+            persist: false,
+        });
+
+        // Chat pulls the file right away, the browser uploads it like it does for other peers
+        let requested = browser.recv("file_requested").await;
+        assert_eq!(requested["id"], id.to_string());
+        let request_id = requested["request_id"].as_str().unwrap().parse::<Uuid>().unwrap();
+
+        let data = futures::stream::once(async { Ok::<_, std::convert::Infallible>(bytes::Bytes::from_static(b"hello")) });
+        service.session_service.upload_file(id, request_id, seed, 5, data).await.unwrap();
+
+        let document = testing::wait_for(|| api.documents.lock().unwrap().first().cloned()).await;
+        assert_eq!(document, (CHAT_ID, "notes.txt".to_owned(), b"hello".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn documents_are_added_to_session() {
+        let api = Arc::new(FakeBotApi::with_file("doc-1", b"document data"));
+        let Joined {
+            service,
+            mut chats,
+            mut browser,
+            seed,
+        } = join(api.clone()).await;
+
+        let document = Document {
+            file_id: "doc-1".to_owned(),
+            file_name: Some("doc.txt".to_owned()),
+            mime_type: Some("text/plain".to_owned()),
+            file_size: None,
+        };
+        service.handle_message(&mut chats, message(None, Some(document))).await;
+
+        let file = browser.recv("file_added").await;
+        assert_eq!(file["name"], "doc.txt");
+        assert_eq!(file["size"], 13);
+        let id = file["id"].as_str().unwrap().parse::<Uuid>().unwrap();
+
+        let read = |range: Option<&str>| {
+            let range = range.map(|range| range.parse::<RangeSpec>().unwrap());
+            let session_service = service.session_service.clone();
+            let seed = seed.clone();
+            async move {
+                let mut data = session_service.request_file(id, seed, range, None).await.unwrap().data;
+                let mut document = Vec::new();
+                while let Some(part) = data.next().await {
+                    document.extend_from_slice(&part.unwrap());
+                }
+                document
+            }
+        };
+
+        assert_eq!(read(None).await, b"document data");
+        assert_eq!(read(Some("bytes=2-7")).await, b"cument");
+    }
+
+    const CHAT_ID: ChatId = 42;
+}
@@ -0,0 +1,281 @@
+use futures::channel::mpsc::UnboundedSender;
+use futures::StreamExt;
+use uuid::Uuid;
+use warp::ws;
+
+use super::api::{BotApi, ChatId, Document, FileStream};
+use crate::prelude::*;
+use crate::services::sessions::{ByteRange, DeviceType, PeerProfile, SessionService, WsRequest, PROTOCOL_V2};
+
+#[derive(Debug)]
+pub enum ChatCommand {
+    AddDocument(Document),
+}
+
+/// Session peer which represents the Telegram chat
+pub struct ChatPeer {
+    chat_id: ChatId,
+    api: Arc<dyn BotApi>,
+    session_service: Arc<SessionService>,
+    /// Telegram paths and sizes of the documents shared by the chat
+    documents: HashMap<Uuid, (String, usize)>,
+    connection_id: Option<usize>,
+    seed: Option<String>,
+}
+
+impl ChatPeer {
+    pub fn new(chat_id: ChatId, api: Arc<dyn BotApi>, session_service: Arc<SessionService>) -> Self {
+        Self {
+            chat_id,
+            api,
+            session_service,
+            documents: Default::default(),
+            connection_id: None,
+            seed: None,
+        }
+    }
+
+    /// Pairs with the peer which shows the phrase and serves the chat until it leaves or the session ends
+    pub async fn run(mut self, phrase: String, mut commands: mpsc::UnboundedReceiver<ChatCommand>) {
+        let (tx, mut rx) = self.session_service.clone().connect_local();
+        send_request(
+            &tx,
+            &WsRequest::Hello {
+                version: PROTOCOL_V2,
+                profile: Some(PeerProfile {
+                    name: "Telegram".to_owned(),
+                    device_type: DeviceType::Bot,
+                    user_agent: None,
+                }),
+            },
+        );
+        send_request(&tx, &WsRequest::Connect { phrase });
+
+        loop {
+            tokio::select! {
+                message = rx.next() => match message {
+                    Some(message) => {
+                        if let Ok(Ok(event)) = message.to_str().map(serde_json::from_str::<PeerEvent>) {
+                            if !self.handle_event(event).await {
+                                break;
+                            }
+                        }
+                    }
+                    None => break,
+                },
+                command = commands.recv() => match command {
+                    Some(ChatCommand::AddDocument(document)) => self.add_document(&tx, document).await,
+                    None => break,
+                },
+            }
+        }
+
+        log::debug!("telegram chat {} left the session", self.chat_id);
+    }
+
+    /// Returns `false` once the chat can't stay in the session
+    async fn handle_event(&mut self, event: PeerEvent) -> bool {
+        match event {
+            PeerEvent::Connected {
+                connection_id,
+                seed,
+                files,
+            } => {
+                self.connection_id = Some(connection_id);
+                self.seed = Some(seed);
+                self.reply("Joined the session, its files will be sent here").await;
+
+                for file in files {
+                    self.send_file(file);
+                }
+            }
+            PeerEvent::FileAdded(file) => self.send_file(file),
+            PeerEvent::FileRequested { id, request_id, range } => self.upload_document(id, request_id, range),
+            PeerEvent::Error { code, message } if code == "peer_not_found" || code == "session_not_found" => {
+                log::debug!("telegram chat {} failed to join: {}", self.chat_id, message);
+                self.reply("Session with this phrase is not found").await;
+                return false;
+            }
+            PeerEvent::Error { message, .. } => self.reply(&format!("Error: {}", message)).await,
+            PeerEvent::SessionExpired => {
+                self.reply("Session has expired").await;
+                return false;
+            }
+            PeerEvent::Kicked => {
+                self.reply("Removed from the session by its host").await;
+                return false;
+            }
+            PeerEvent::Other => {}
+        }
+
+        true
+    }
+
+    /// Pulls the file added by another peer and sends it as a document
+    fn send_file(&self, file: PeerFile) {
+        let (seed, connection_id) = match (&self.seed, self.connection_id) {
+            (Some(seed), Some(connection_id)) if file.connection_id != connection_id => (seed.clone(), connection_id),
+            _ => return,
+        };
+
+        let chat_id = self.chat_id;
+        let api = self.api.clone();
+        let session_service = self.session_service.clone();
+        tokio::spawn(async move {
+            if file.size > MAX_SEND_SIZE {
+                let _ = api
+                    .send_message(chat_id, &format!("{} is too large to be sent to Telegram", file.name))
+                    .await;
+                return;
+            }
+
+            let result = match session_service.request_file(file.id, seed, None, Some(connection_id)).await {
+                Ok(request) => api.send_document(chat_id, &file.name, &file.mime_type, request.data).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                log::warn!("failed to send file {} to telegram: {}", file.id, e);
+            }
+        });
+    }
+
+    /// Announces the document sent to the bot as a new file of the session
+    async fn add_document(&mut self, tx: &UnboundedSender<ws::Message>, document: Document) {
+        if self.seed.is_none() {
+            self.reply("Wait until the session is joined").await;
+            return;
+        }
+
+        let file = match self.api.get_file(&document.file_id).await {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!("failed to get telegram file: {}", e);
+                self.reply("Failed to get the document").await;
+                return;
+            }
+        };
+
+        let (path, size) = match (file.file_path, file.file_size.or(document.file_size)) {
+            (Some(path), Some(size)) => (path, size),
+            _ => {
+                self.reply("Document is too large to be shared by the bot").await;
+                return;
+            }
+        };
+
+        let id = Uuid::new_v4();
+        self.documents.insert(id, (path, size));
+        send_request(
+            tx,
+            &WsRequest::AddFile {
+                id,
+                name: document.file_name.unwrap_or_else(|| id.to_string()),
+                mime_type: document.mime_type.unwrap_or_else(|| "application/octet-stream".to_owned()),
+                size,
+                hash: None,
+                persist: false,
+            },
+        );
+    }
+
+    /// Answers the request of another peer with the document downloaded from Telegram
+    fn upload_document(&self, id: Uuid, request_id: Uuid, range: Option<ByteRange>) {
+        let ((path, size), seed) = match (self.documents.get(&id), &self.seed) {
+            (Some(document), Some(seed)) => (document.clone(), seed.clone()),
+            _ => return,
+        };
+        let size = range.map(|range| range.len()).unwrap_or(size);
+
+        let api = self.api.clone();
+        let session_service = self.session_service.clone();
+        tokio::spawn(async move {
+            let data = match api.download_file(&path).await {
+                Ok(data) => data,
+                Err(e) => {
+                    log::warn!("failed to download telegram file: {}", e);
+                    return;
+                }
+            };
+
+            if let Err(e) = session_service.upload_file(id, request_id, seed, size, slice(data, range)).await {
+                log::debug!("upload of telegram file {} failed: {}", id, e);
+            }
+        });
+    }
+
+    async fn reply(&self, text: &str) {
+        if let Err(e) = self.api.send_message(self.chat_id, text).await {
+            log::warn!("failed to send telegram message: {}", e);
+        }
+    }
+}
+
+fn send_request(tx: &UnboundedSender<ws::Message>, request: &WsRequest) {
+    match serde_json::to_string(request) {
+        Ok(request) => {
+            let _ = tx.unbounded_send(ws::Message::text(request));
+        }
+        Err(e) => log::error!("failed to encode request: {}", e),
+    }
+}
+
+/// Skips the bytes outside of the range, Telegram downloads always start from the beginning
+fn slice(data: FileStream, range: Option<ByteRange>) -> FileStream {
+    let range = match range {
+        Some(range) => range,
+        None => return data,
+    };
+
+    data.scan(0, move |offset, part| {
+        let start = *offset;
+        if start > range.end {
+            return futures::future::ready(None);
+        }
+
+        let part = part.map(|mut part| {
+            *offset += part.len();
+            part.truncate((range.end + 1 - start).min(part.len()));
+            part.split_off(range.start.saturating_sub(start).min(part.len()))
+        });
+        futures::future::ready(Some(part))
+    })
+    .filter(|part| futures::future::ready(!matches!(part, Ok(part) if part.is_empty())))
+    .boxed()
+}
+
+/// Messages of the session which the chat reacts to
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", content = "content", rename_all = "snake_case")]
+enum PeerEvent {
+    Connected {
+        connection_id: usize,
+        seed: String,
+        files: Vec<PeerFile>,
+    },
+    FileAdded(PeerFile),
+    FileRequested {
+        id: Uuid,
+        request_id: Uuid,
+        range: Option<ByteRange>,
+    },
+    Error {
+        code: String,
+        message: String,
+    },
+    SessionExpired,
+    Kicked,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct PeerFile {
+    id: Uuid,
+    name: String,
+    mime_type: String,
+    size: usize,
+    connection_id: usize,
+}
+
+/// Limit of documents sent by bots
+const MAX_SEND_SIZE: usize = 50 * 1024 * 1024;
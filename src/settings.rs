@@ -1,13 +1,29 @@
 use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use anyhow::Result;
 use config::{Config, File, FileFormat};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Settings {
     pub password: String,
     pub server_addr: SocketAddr,
+    #[serde(default)]
+    pub relay: RelaySettings,
+    #[serde(default)]
+    pub sessions: SessionSettings,
+    #[serde(default)]
+    pub heartbeat: HeartbeatSettings,
+    #[serde(default)]
+    pub queue: QueueSettings,
+    #[serde(default)]
+    pub rtc: RtcSettings,
+    /// Store-and-forward mode is disabled if not specified
+    pub storage: Option<StorageSettings>,
+    /// Telegram bot is started only if specified
+    pub telegram: Option<TelegramSettings>,
 }
 
 impl Settings {
@@ -19,3 +35,247 @@ impl Settings {
         Ok(settings)
     }
 }
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RelaySettings {
+    /// Feed every waiting downloader of the same file from a single upload
+    pub broadcast: bool,
+    /// Max number of bytes buffered in memory for each downloader
+    pub buffer_size: usize,
+    /// How long a slow downloader may stall a shared upload before it is dropped
+    pub max_lag_ms: u64,
+    /// How long archives wait for the owner to start uploading each file
+    pub request_timeout_ms: u64,
+}
+
+impl RelaySettings {
+    #[inline]
+    pub fn max_lag(&self) -> Duration {
+        Duration::from_millis(self.max_lag_ms)
+    }
+
+    #[inline]
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_timeout_ms)
+    }
+}
+
+impl Default for RelaySettings {
+    fn default() -> Self {
+        Self {
+            broadcast: false,
+            buffer_size: 1024 * 1024,
+            max_lag_ms: 10_000,
+            request_timeout_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionSettings {
+    /// How long a pairing phrase stays valid, in seconds
+    pub pending_ttl_secs: u64,
+    /// How long a session may stay without file activity, in seconds
+    pub idle_ttl_secs: u64,
+    /// Absolute session lifetime, in seconds
+    pub max_lifetime_secs: u64,
+    /// How long files of a disconnected peer are kept until it resumes, in seconds
+    pub resume_grace_secs: u64,
+}
+
+impl SessionSettings {
+    #[inline]
+    pub fn pending_ttl(&self) -> Duration {
+        Duration::from_secs(self.pending_ttl_secs)
+    }
+
+    #[inline]
+    pub fn idle_ttl(&self) -> Duration {
+        Duration::from_secs(self.idle_ttl_secs)
+    }
+
+    #[inline]
+    pub fn max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.max_lifetime_secs)
+    }
+
+    #[inline]
+    pub fn resume_grace(&self) -> Duration {
+        Duration::from_secs(self.resume_grace_secs)
+    }
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        Self {
+            pending_ttl_secs: 10 * 60,
+            idle_ttl_secs: 60 * 60,
+            max_lifetime_secs: 24 * 60 * 60,
+            resume_grace_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HeartbeatSettings {
+    /// How often websocket pings are sent
+    pub interval_ms: u64,
+    /// Connection is dropped after this number of pings without pong
+    pub max_missed_pongs: usize,
+}
+
+impl HeartbeatSettings {
+    #[inline]
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms.max(1))
+    }
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        Self {
+            interval_ms: 15_000,
+            max_missed_pongs: 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct QueueSettings {
+    /// Max number of outgoing websocket messages queued for each connection
+    pub capacity: usize,
+    /// What happens when a peer doesn't read its messages fast enough
+    pub policy: QueuePolicy,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: QueuePolicy::Disconnect,
+        }
+    }
+}
+
+/// There is no policy which blocks the sender until the queue has room: messages are queued
+/// synchronously while the session lock is held, so a slow peer would stall its whole session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePolicy {
+    /// Drop the connection of the slow peer
+    Disconnect,
+    /// Replace queued messages which are made obsolete by the newer ones, drop the connection
+    /// if nothing can be replaced
+    Coalesce,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtcSettings {
+    /// Relay WebRTC signalling, so peers can transfer files directly
+    pub enabled: bool,
+    /// STUN and TURN servers announced to clients
+    pub ice_servers: Vec<IceServer>,
+}
+
+impl Default for RtcSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ice_servers: vec![IceServer {
+                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+                username: None,
+                credential: None,
+            }],
+        }
+    }
+}
+
+/// Same shape as `RTCIceServer` of the browser API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StorageSettings {
+    #[serde(default)]
+    pub backend: StorageBackend,
+    /// Directory for the uploaded files, used by the `fs` backend
+    #[serde(default = "default_storage_directory")]
+    pub directory: PathBuf,
+    /// Bucket and credentials, required by the `s3` backend
+    pub s3: Option<S3Settings>,
+    /// How long uploaded files are kept, in seconds
+    pub ttl_secs: u64,
+}
+
+impl StorageSettings {
+    #[inline]
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    #[default]
+    Fs,
+    Memory,
+    S3,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct S3Settings {
+    /// Base url of the S3 compatible API, e.g. `http://localhost:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelegramSettings {
+    /// Token issued by @BotFather
+    pub token: String,
+    /// Base url of the Bot API, may point to a local Bot API server
+    #[serde(default = "default_telegram_api_url")]
+    pub api_url: String,
+    /// How long each `getUpdates` request waits for new messages, in seconds
+    #[serde(default = "default_telegram_poll_timeout_secs")]
+    pub poll_timeout_secs: u64,
+}
+
+impl TelegramSettings {
+    #[inline]
+    pub fn poll_timeout(&self) -> Duration {
+        Duration::from_secs(self.poll_timeout_secs)
+    }
+}
+
+fn default_storage_directory() -> PathBuf {
+    PathBuf::from("storage")
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_telegram_api_url() -> String {
+    "https://api.telegram.org".to_owned()
+}
+
+fn default_telegram_poll_timeout_secs() -> u64 {
+    30
+}